@@ -0,0 +1,93 @@
+// Tasks with due dates as an iCalendar feed (RFC 5545), for calendar apps to subscribe to
+
+use chrono::{DateTime, Utc};
+
+use crate::{Project, State, Task};
+
+const TIME_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+// Longest a content line may be, in octets, before it has to be folded
+const MAX_LINE_OCTETS: usize = 75;
+
+// Escapes a TEXT value as section 3.3.11 asks
+fn escape_text(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' | ';' | ',' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Folds the line onto continuation lines, which start with a space, without splitting characters
+fn write_line(output: &mut String, line: &str) {
+    let mut octets = 0;
+    for c in line.chars() {
+        if octets + c.len_utf8() > MAX_LINE_OCTETS {
+            output.push_str("\r\n ");
+            octets = 1;
+        }
+        output.push(c);
+        octets += c.len_utf8();
+    }
+    output.push_str("\r\n");
+}
+
+fn write_event(
+    output: &mut String,
+    project: &Project,
+    task: &Task,
+    due_date: DateTime<Utc>,
+    now: &str,
+) {
+    write_line(output, "BEGIN:VEVENT");
+    // Task IDs are never reused within a project, so the UID keeps pointing at the same task
+    write_line(
+        output,
+        &format!("UID:task-{}-{}@btasks", project.id, task.id),
+    );
+    write_line(output, &format!("DTSTAMP:{}", now));
+    write_line(output, &format!("DTSTART:{}", due_date.format(TIME_FORMAT)));
+    write_line(output, &format!("SUMMARY:{}", escape_text(&task.title)));
+    if !task.description.is_empty() {
+        write_line(
+            output,
+            &format!("DESCRIPTION:{}", escape_text(&task.description)),
+        );
+    }
+    write_line(
+        output,
+        &format!("CATEGORIES:{}", escape_text(&project.name)),
+    );
+    if task.state == State::Cancelled {
+        write_line(output, "STATUS:CANCELLED");
+    }
+    write_line(output, "END:VEVENT");
+}
+
+// One event per task with a due date, at the due date
+pub(crate) fn render<'a>(
+    tasks: impl Iterator<Item = (&'a Project, &'a Task)>,
+    now: DateTime<Utc>,
+) -> String {
+    let now = now.format(TIME_FORMAT).to_string();
+    let mut output = String::new();
+    write_line(&mut output, "BEGIN:VCALENDAR");
+    write_line(&mut output, "VERSION:2.0");
+    write_line(&mut output, "PRODID:-//btasks//btasks//EN");
+    write_line(&mut output, "CALSCALE:GREGORIAN");
+    for (project, task) in tasks {
+        if let Some(due_date) = task.due_date {
+            write_event(&mut output, project, task, due_date, &now);
+        }
+    }
+    write_line(&mut output, "END:VCALENDAR");
+    output
+}
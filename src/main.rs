@@ -1,15 +1,28 @@
-use std::collections::HashSet;
-use std::fs::File;
-use std::net::SocketAddr;
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
 
+use hyper::body::HttpBody;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use tracing::Instrument;
+use webhooks::{Webhook, WebhookEvent};
 
-#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+mod ical;
+mod metrics;
+mod prometheus;
+mod query;
+mod webhooks;
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
 enum State {
     Todo,
     InProgress,
@@ -18,17 +31,78 @@ enum State {
     Done,
 }
 
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Default)]
+enum Priority {
+    Low,
+    #[default]
+    Medium,
+    High,
+    Critical,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 enum LogEntryType {
     Comment(String),
     StateChangedTo(State),
+    PriorityChangedTo(Priority),
+    // Null when the due date was cleared
+    DueDateChanged(
+        #[serde(with = "chrono::serde::ts_seconds_option")] Option<chrono::DateTime<chrono::Utc>>,
+    ),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct LogEntry {
+    #[serde(default)]
+    id: usize,
     #[serde(with = "chrono::serde::ts_seconds")]
     timestamp: chrono::DateTime<chrono::Utc>,
     entry_type: LogEntryType,
+    #[serde(default)]
+    pinned: bool,
+    // When a comment's text was last changed, if it ever was
+    #[serde(default, with = "chrono::serde::ts_seconds_option")]
+    edited_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+// Links between tasks which don't affect scheduling. Scheduling relationships are tracked
+// separately in Task::dependencies.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+enum RelationKind {
+    RelatesTo,
+    DuplicateOf,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+struct Relation {
+    kind: RelationKind,
+    task_id: usize,
+}
+
+// A file kept somewhere else, which the task links to. btasks only stores what it is told about
+// the file.
+#[derive(Serialize, Deserialize, Debug)]
+struct Attachment {
+    filename: String,
+    url: String,
+    #[serde(default)]
+    size_bytes: Option<u64>,
+    #[serde(default)]
+    content_type: Option<String>,
+}
+
+// Small steps within a task, which don't need to be tasks of their own
+#[derive(Serialize, Deserialize, Debug)]
+struct ChecklistItem {
+    text: String,
+    #[serde(default)]
+    done: bool,
+}
+
+// Stands in for when things were created, for databases written before that was recorded
+fn unix_epoch() -> chrono::DateTime<chrono::Utc> {
+    use chrono::TimeZone;
+    chrono::Utc.timestamp_opt(0, 0).unwrap()
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -38,16 +112,112 @@ struct Task {
     id: usize,
     log: Vec<LogEntry>,
     state: State,
+    #[serde(default)]
+    priority: Priority,
     dependencies: HashSet<usize>,
+    #[serde(default)]
+    assignee: Option<String>,
+    #[serde(default)]
+    estimate_minutes: Option<u64>,
+    // Time actually spent on the task, for comparing against the estimate
+    #[serde(default)]
+    actual_minutes: Option<u64>,
+    #[serde(default)]
+    next_log_id: usize,
+    #[serde(default)]
+    dropped_log_entries: usize,
+    #[serde(default)]
+    relations: Vec<Relation>,
+    #[serde(default)]
+    attachments: Vec<Attachment>,
+    #[serde(default)]
+    tags: HashSet<String>,
+    #[serde(default, with = "chrono::serde::ts_seconds_option")]
+    due_date: Option<chrono::DateTime<chrono::Utc>>,
+    // Bumped on every change to the task, so clients can cheaply tell whether it changed
+    #[serde(default)]
+    version: u64,
+    // Hex color picked by the user to highlight the task, e.g. "#ff0000"
+    #[serde(default)]
+    color: Option<String>,
+    // Archived tasks are left out of project views unless asked for
+    #[serde(default)]
+    archived: bool,
+    #[serde(default)]
+    checklist: Vec<ChecklistItem>,
+    // Task this one sits under in the project's outline. Unrelated to dependencies.
+    #[serde(default)]
+    parent: Option<usize>,
+    #[serde(default = "unix_epoch", with = "chrono::serde::ts_seconds")]
+    created_at: chrono::DateTime<chrono::Utc>,
 }
 
 impl Task {
-    fn new_log_entry(&mut self, entry_type: LogEntryType) {
+    fn new_log_entry(&mut self, entry_type: LogEntryType, max_log_length: Option<usize>) {
         let timestamp = chrono::Utc::now();
+        let id = self.next_log_id;
+        self.next_log_id += 1;
         self.log.push(LogEntry {
+            id,
             timestamp,
             entry_type,
+            pinned: false,
+            edited_at: None,
         });
+        if let Some(max_log_length) = max_log_length {
+            self.rotate_log(max_log_length);
+        }
+    }
+
+    // Drops the oldest unpinned comments until the log fits within the given length. Other kinds
+    // of entries and pinned comments are never dropped, so the log may still end up longer.
+    fn rotate_log(&mut self, max_log_length: usize) {
+        while self.log.len() > max_log_length {
+            let droppable = self.log.iter().position(|entry| {
+                !entry.pinned && matches!(entry.entry_type, LogEntryType::Comment(_))
+            });
+            match droppable {
+                Some(entry_index) => {
+                    self.log.remove(entry_index);
+                    self.dropped_log_entries += 1;
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn log_entry_position(&self, id: usize) -> Result<usize, ApiError> {
+        self.log
+            .binary_search_by_key(&id, |entry| entry.id)
+            .map_err(|_| ApiError::LogEntryNotFound(id))
+    }
+
+    fn find_log_entry_by_id_mut(&mut self, id: usize) -> Result<&mut LogEntry, ApiError> {
+        let entry_index = self.log_entry_position(id)?;
+        Ok(&mut self.log[entry_index])
+    }
+
+    // Like find_log_entry_by_id_mut, but fails unless the entry is a comment
+    fn find_comment_by_id_mut(&mut self, id: usize) -> Result<&mut LogEntry, ApiError> {
+        let entry = self.find_log_entry_by_id_mut(id)?;
+        if !matches!(entry.entry_type, LogEntryType::Comment(_)) {
+            return Err(ApiError::InvalidValue(format!(
+                "Log entry {} is not a comment",
+                id
+            )));
+        }
+        Ok(entry)
+    }
+
+    // Databases written before log entries had IDs load with every ID defaulted to 0. Number
+    // those entries by their position in the log instead.
+    fn backfill_log_ids(&mut self) {
+        if self.next_log_id == 0 && !self.log.is_empty() {
+            for (index, entry) in self.log.iter_mut().enumerate() {
+                entry.id = index;
+            }
+            self.next_log_id = self.log.len();
+        }
     }
 }
 
@@ -58,74 +228,411 @@ struct Project {
     id: usize,
     tasks: Vec<Task>,
     next_task_id: usize,
+    #[serde(default)]
+    group: Option<String>,
+    #[serde(default = "unix_epoch", with = "chrono::serde::ts_seconds")]
+    created_at: chrono::DateTime<chrono::Utc>,
+    // Position of each task in `tasks`, by ID. Not stored, so it has to be rebuilt with
+    // reindex_tasks whenever a project is loaded, or its tasks are reordered.
+    #[serde(skip)]
+    task_positions: HashMap<usize, usize>,
 }
 
 impl Project {
-    fn find_task_by_id(&self, id: usize) -> Result<&Task, Box<dyn std::error::Error>> {
-        let task_index = self
+    fn reindex_tasks(&mut self) {
+        self.task_positions = self
             .tasks
-            .binary_search_by_key(&id, |task| task.id)
-            .map_err(|_| format!("Could not find task with ID: {}", id))?;
+            .iter()
+            .enumerate()
+            .map(|(index, task)| (task.id, index))
+            .collect();
+    }
+
+    fn task_position(&self, id: usize) -> Result<usize, ApiError> {
+        self.task_positions
+            .get(&id)
+            .copied()
+            .ok_or(ApiError::TaskNotFound(id))
+    }
+
+    fn find_task_by_id(&self, id: usize) -> Result<&Task, ApiError> {
+        let task_index = self.task_position(id)?;
         Ok(&self.tasks[task_index])
     }
 
-    fn find_task_by_id_mut(&mut self, id: usize) -> Result<&mut Task, Box<dyn std::error::Error>> {
-        let task_index = self
-            .tasks
-            .binary_search_by_key(&id, |task| task.id)
-            .map_err(|_| format!("Could not find task with ID: {}", id))?;
+    fn find_task_by_id_mut(&mut self, id: usize) -> Result<&mut Task, ApiError> {
+        let task_index = self.task_position(id)?;
         Ok(&mut self.tasks[task_index])
     }
 
     fn create_task(&mut self, title: String, description: String) -> usize {
-        let id = self.next_task_id;
-        self.next_task_id += 1;
         let task = Task {
             title,
             description,
-            id,
+            // Given by insert_task
+            id: 0,
             state: State::Todo,
+            priority: Priority::Medium,
             log: Vec::new(),
             dependencies: HashSet::new(),
+            assignee: None,
+            estimate_minutes: None,
+            actual_minutes: None,
+            next_log_id: 0,
+            dropped_log_entries: 0,
+            relations: Vec::new(),
+            attachments: Vec::new(),
+            tags: HashSet::new(),
+            due_date: None,
+            version: 0,
+            color: None,
+            archived: false,
+            checklist: Vec::new(),
+            parent: None,
+            created_at: chrono::Utc::now(),
         };
+        self.insert_task(task)
+    }
+
+    // Adds a task to the project under a fresh ID, which is returned
+    fn insert_task(&mut self, mut task: Task) -> usize {
+        let id = self.next_task_id;
+        self.next_task_id += 1;
+        task.id = id;
+        self.task_positions.insert(id, self.tasks.len());
         self.tasks.push(task);
         id
     }
 
-    fn remove_task(&mut self, task_id: usize) -> Result<(), Box<dyn std::error::Error>> {
-        let task_index = self
+    // Also drops the task from the dependencies and relations of every other task, and moves its
+    // children to the top level, so nothing is left pointing at it. Returns the removed task if it
+    // existed, along with the IDs of the tasks which depended on it.
+    fn remove_task(&mut self, task_id: usize) -> Option<(Task, Vec<usize>)> {
+        let task_index = self.task_position(task_id).ok()?;
+        let task = self.tasks.remove(task_index);
+        self.reindex_tasks();
+        let mut unlinked = Vec::new();
+        for other in &mut self.tasks {
+            let depended = other.dependencies.remove(&task_id);
+            let relations = other.relations.len();
+            other
+                .relations
+                .retain(|relation| relation.task_id != task_id);
+            let related = other.relations.len() < relations;
+            let child = other.parent == Some(task_id);
+            if child {
+                other.parent = None;
+            }
+            if depended || related || child {
+                other.version += 1;
+            }
+            if depended {
+                unlinked.push(other.id);
+            }
+        }
+        Some((task, unlinked))
+    }
+
+    // The tasks which directly depend on the given one
+    fn dependents_of(&self, task_id: usize) -> impl Iterator<Item = &Task> {
+        self.tasks
+            .iter()
+            .filter(move |task| task.dependencies.contains(&task_id))
+    }
+
+    // Looks for a cycle in the dependency graph. If there is one, returns the IDs of the tasks
+    // along it, starting and ending with the same task.
+    fn find_dependency_cycle(&self) -> Option<Vec<usize>> {
+        let mut finished = HashSet::new();
+        let mut path = Vec::new();
+        self.tasks
+            .iter()
+            .find_map(|task| self.find_cycle_from(task.id, &mut path, &mut finished))
+    }
+
+    fn find_cycle_from(
+        &self,
+        task_id: usize,
+        path: &mut Vec<usize>,
+        finished: &mut HashSet<usize>,
+    ) -> Option<Vec<usize>> {
+        if finished.contains(&task_id) {
+            return None;
+        }
+        if let Some(start) = path.iter().position(|id| *id == task_id) {
+            let mut cycle = path[start..].to_vec();
+            cycle.push(task_id);
+            return Some(cycle);
+        }
+        // Dangling dependencies lead nowhere
+        let task = self.find_task_by_id(task_id).ok()?;
+        let mut dependencies = task.dependencies.iter().copied().collect::<Vec<_>>();
+        dependencies.sort_unstable();
+        path.push(task_id);
+        for dependency in dependencies {
+            if let Some(cycle) = self.find_cycle_from(dependency, path, finished) {
+                return Some(cycle);
+            }
+        }
+        path.pop();
+        finished.insert(task_id);
+        None
+    }
+
+    // Checks that a project from outside, e.g. an import, is consistent. Sorts its tasks by ID
+    // along the way, so they list in the same order as everywhere else.
+    fn validate_external(&mut self) -> Vec<String> {
+        let mut errors = Vec::new();
+        self.tasks.sort_by_key(|task| task.id);
+        self.reindex_tasks();
+        for pair in self.tasks.windows(2) {
+            if pair[0].id == pair[1].id {
+                errors.push(format!("Duplicate task ID: {}", pair[0].id));
+            }
+        }
+        for task in &self.tasks {
+            let mut dependencies = task.dependencies.iter().copied().collect::<Vec<_>>();
+            dependencies.sort_unstable();
+            for dependency in dependencies {
+                if self.find_task_by_id(dependency).is_err() {
+                    errors.push(format!(
+                        "Task {} depends on missing task {}",
+                        task.id, dependency
+                    ));
+                }
+            }
+        }
+        if let Some(cycle) = self.find_dependency_cycle() {
+            errors.push(format!("Dependency cycle: {:?}", cycle));
+        }
+        errors
+    }
+
+    // Numbers the tasks from 0 in their current order, following dependencies, relations and
+    // parents along to the new IDs. Links to tasks which aren't in the project are dropped.
+    fn renumber_tasks(&mut self) {
+        let new_ids = self
             .tasks
-            .binary_search_by_key(&task_id, |task| task.id)
-            .map_err(|_| format!("Could not find task with ID: {}", task_id))?;
-        self.tasks.remove(task_index);
-        Ok(())
+            .iter()
+            .enumerate()
+            .map(|(new_id, task)| (task.id, new_id))
+            .collect::<HashMap<_, _>>();
+        for task in &mut self.tasks {
+            task.id = new_ids[&task.id];
+            task.dependencies = task
+                .dependencies
+                .iter()
+                .filter_map(|id| new_ids.get(id).copied())
+                .collect();
+            task.relations
+                .retain_mut(|relation| match new_ids.get(&relation.task_id) {
+                    Some(new_id) => {
+                        relation.task_id = *new_id;
+                        true
+                    }
+                    None => false,
+                });
+            task.parent = task.parent.and_then(|id| new_ids.get(&id).copied());
+        }
+        self.next_task_id = self.tasks.len();
+        self.reindex_tasks();
+    }
+
+    // Whether the task is the given ancestor, or sits somewhere under it in the outline
+    fn is_under(&self, task_id: usize, ancestor: usize) -> bool {
+        let mut visited = HashSet::new();
+        let mut current = Some(task_id);
+        while let Some(id) = current {
+            if id == ancestor {
+                return true;
+            }
+            if !visited.insert(id) {
+                return false;
+            }
+            current = self.find_task_by_id(id).ok().and_then(|task| task.parent);
+        }
+        false
+    }
+
+    // Finds the shortest chain of dependencies leading from one task to another, if there is one.
+    // The chain starts with `from` and ends with `to`.
+    fn dependency_path(&self, from: usize, to: usize) -> Option<Vec<usize>> {
+        let mut came_from = HashMap::new();
+        let mut queue = std::collections::VecDeque::from([from]);
+        while let Some(current) = queue.pop_front() {
+            if current == to {
+                let mut path = vec![to];
+                let mut id = to;
+                while let Some(previous) = came_from.get(&id) {
+                    path.push(*previous);
+                    id = *previous;
+                }
+                path.reverse();
+                return Some(path);
+            }
+            let task = match self.find_task_by_id(current) {
+                Ok(task) => task,
+                Err(_) => continue,
+            };
+            let mut dependencies = task.dependencies.iter().copied().collect::<Vec<_>>();
+            dependencies.sort_unstable();
+            for dependency in dependencies {
+                if dependency != from && !came_from.contains_key(&dependency) {
+                    came_from.insert(dependency, current);
+                    queue.push_back(dependency);
+                }
+            }
+        }
+        None
+    }
+
+    // Returns the given task along with every task it transitively depends on. Dangling
+    // dependencies are left out.
+    fn dependency_closure(&self, task_id: usize) -> HashSet<usize> {
+        let mut visited = HashSet::new();
+        let mut stack = vec![task_id];
+        while let Some(current) = stack.pop() {
+            let task = match self.find_task_by_id(current) {
+                Ok(task) => task,
+                Err(_) => continue,
+            };
+            if visited.insert(current) {
+                stack.extend(task.dependencies.iter().copied());
+            }
+        }
+        visited
+    }
+
+    // Maps each task ID to the IDs of tasks which directly depend on it
+    fn dependents_map(&self) -> HashMap<usize, Vec<usize>> {
+        let mut dependents = HashMap::<usize, Vec<usize>>::new();
+        for task in &self.tasks {
+            for dependency in &task.dependencies {
+                dependents.entry(*dependency).or_default().push(task.id);
+            }
+        }
+        dependents
+    }
+
+    // Returns every task which transitively depends on the given task, and whether the task was
+    // found to be part of a dependency cycle along the way
+    fn transitive_dependents(
+        task_id: usize,
+        dependents: &HashMap<usize, Vec<usize>>,
+    ) -> (HashSet<usize>, bool) {
+        let mut visited = HashSet::new();
+        let mut stack = vec![task_id];
+        let mut in_cycle = false;
+        while let Some(current) = stack.pop() {
+            for dependent in dependents.get(&current).into_iter().flatten() {
+                if *dependent == task_id {
+                    in_cycle = true;
+                } else if visited.insert(*dependent) {
+                    stack.push(*dependent);
+                }
+            }
+        }
+        (visited, in_cycle)
+    }
+}
+
+// The database file stores projects as a list, but in memory they're kept in a map keyed by ID,
+// so that finding a project never depends on what order the projects are in
+mod projects_as_list {
+    use std::collections::BTreeMap;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::Project;
+
+    pub fn serialize<S: Serializer>(
+        projects: &BTreeMap<usize, Project>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(projects.values())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<usize, Project>, D::Error> {
+        let projects = Vec::<Project>::deserialize(deserializer)?;
+        Ok(projects
+            .into_iter()
+            .map(|project| (project.id, project))
+            .collect())
     }
 }
 
 #[derive(Default, Serialize, Deserialize, Debug)]
 struct Database {
-    projects: Vec<Project>,
+    #[serde(with = "projects_as_list")]
+    projects: BTreeMap<usize, Project>,
     next_project_id: usize,
+    #[serde(default)]
+    saved_queries: BTreeMap<String, TaskQuery>,
+    #[serde(default)]
+    share_tokens: BTreeMap<String, ShareToken>,
+    // Creations and deletions of projects and tasks, oldest first
+    #[serde(default)]
+    history: Vec<HistoryEvent>,
+    // Bumped on every change to the database, so clients can cheaply tell whether anything changed
+    #[serde(default)]
+    revision: u64,
+    #[serde(default)]
+    webhooks: BTreeMap<usize, Webhook>,
+    #[serde(default)]
+    next_webhook_id: usize,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+enum HistoryEventKind {
+    ProjectCreated,
+    ProjectImported,
+    ProjectDeleted,
+    TaskCreated,
+    TaskDeleted,
+    TaskArchived,
+    TaskMovedOut,
+    TaskMovedIn,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct HistoryEvent {
+    #[serde(with = "chrono::serde::ts_seconds")]
+    timestamp: chrono::DateTime<chrono::Utc>,
+    kind: HistoryEventKind,
+    project_id: usize,
+    #[serde(default)]
+    task_id: Option<usize>,
+    // Name of the project, or title of the task, at the time. Kept so that events about deleted
+    // items still say what they were about.
+    name: String,
+}
+
+// Grants read access to a single project, to requests which carry the token
+#[derive(Serialize, Deserialize, Debug)]
+struct ShareToken {
+    project_id: usize,
+    // Never expires if not set
+    #[serde(default, with = "chrono::serde::ts_seconds_option")]
+    expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl ShareToken {
+    fn is_expired(&self, now: chrono::DateTime<chrono::Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
 }
 
 impl Database {
-    fn find_project_by_id(&self, id: usize) -> Result<&Project, Box<dyn std::error::Error>> {
-        let project_index = self
-            .projects
-            .binary_search_by_key(&id, |project| project.id)
-            .map_err(|_| format!("Could not find project with ID: {}", id))?;
-        Ok(&self.projects[project_index])
+    fn find_project_by_id(&self, id: usize) -> Result<&Project, ApiError> {
+        self.projects.get(&id).ok_or(ApiError::ProjectNotFound(id))
     }
 
-    fn find_project_by_id_mut(
-        &mut self,
-        id: usize,
-    ) -> Result<&mut Project, Box<dyn std::error::Error>> {
-        let project_index = self
-            .projects
-            .binary_search_by_key(&id, |project| project.id)
-            .map_err(|_| format!("Could not find project with ID: {}", id))?;
-        Ok(&mut self.projects[project_index])
+    fn find_project_by_id_mut(&mut self, id: usize) -> Result<&mut Project, ApiError> {
+        self.projects
+            .get_mut(&id)
+            .ok_or(ApiError::ProjectNotFound(id))
     }
 
     fn create_project(&mut self, name: String, description: String) -> usize {
@@ -137,429 +644,5493 @@ impl Database {
             id,
             tasks: Vec::new(),
             next_task_id: 0,
+            task_positions: HashMap::new(),
+            group: None,
+            created_at: chrono::Utc::now(),
         };
-        self.projects.push(project);
+        self.record_history(HistoryEventKind::ProjectCreated, id, None, &project.name);
+        self.projects.insert(id, project);
         id
     }
 
-    fn remove_project(&mut self, project_id: usize) -> Result<(), Box<dyn std::error::Error>> {
-        let project_index = self
-            .projects
-            .binary_search_by_key(&project_id, |project| project.id)
-            .map_err(|_| format!("Could not find project with ID: {}", project_id))?;
-        self.projects.remove(project_index);
-        Ok(())
+    // Copies a project as a template for a new one. Tasks get fresh IDs, with every reference
+    // between them carried over to the new IDs, and start again from Todo with empty logs.
+    fn duplicate_project(
+        &mut self,
+        project_id: usize,
+        name: Option<String>,
+    ) -> Result<usize, ApiError> {
+        let original = self.find_project_by_id(project_id)?;
+        let new_ids = original
+            .tasks
+            .iter()
+            .enumerate()
+            .map(|(new_id, task)| (task.id, new_id))
+            .collect::<HashMap<_, _>>();
+        let now = chrono::Utc::now();
+        let tasks = original
+            .tasks
+            .iter()
+            .map(|task| Task {
+                title: task.title.clone(),
+                description: task.description.clone(),
+                id: new_ids[&task.id],
+                log: Vec::new(),
+                state: State::Todo,
+                priority: task.priority,
+                // Dangling dependencies have nothing to point to in the copy
+                dependencies: task
+                    .dependencies
+                    .iter()
+                    .filter_map(|id| new_ids.get(id).copied())
+                    .collect(),
+                assignee: task.assignee.clone(),
+                estimate_minutes: task.estimate_minutes,
+                actual_minutes: None,
+                next_log_id: 0,
+                dropped_log_entries: 0,
+                relations: task
+                    .relations
+                    .iter()
+                    .filter_map(|relation| {
+                        Some(Relation {
+                            kind: relation.kind,
+                            task_id: *new_ids.get(&relation.task_id)?,
+                        })
+                    })
+                    .collect(),
+                attachments: task
+                    .attachments
+                    .iter()
+                    .map(|attachment| Attachment {
+                        filename: attachment.filename.clone(),
+                        url: attachment.url.clone(),
+                        size_bytes: attachment.size_bytes,
+                        content_type: attachment.content_type.clone(),
+                    })
+                    .collect(),
+                tags: task.tags.clone(),
+                due_date: task.due_date,
+                version: 0,
+                color: task.color.clone(),
+                archived: false,
+                checklist: task
+                    .checklist
+                    .iter()
+                    .map(|item| ChecklistItem {
+                        text: item.text.clone(),
+                        done: false,
+                    })
+                    .collect(),
+                parent: task.parent.and_then(|id| new_ids.get(&id).copied()),
+                created_at: now,
+            })
+            .collect::<Vec<_>>();
+        let id = self.next_project_id;
+        let mut project = Project {
+            name: name.unwrap_or_else(|| format!("{} (copy)", original.name)),
+            description: original.description.clone(),
+            id,
+            next_task_id: tasks.len(),
+            tasks,
+            task_positions: HashMap::new(),
+            group: original.group.clone(),
+            created_at: now,
+        };
+        project.reindex_tasks();
+        self.next_project_id += 1;
+        self.record_history(HistoryEventKind::ProjectCreated, id, None, &project.name);
+        self.projects.insert(id, project);
+        Ok(id)
     }
-}
 
-struct AppState {
-    database: Database,
-}
+    // Tells every webhook registered for the event about it. Only called once the change has been
+    // recorded.
+    fn notify_webhooks(&self, event: WebhookEvent, mut payload: serde_json::Value) {
+        payload["event"] = json!(event);
+        payload["timestamp"] = json!(chrono::Utc::now().timestamp());
+        let body = payload.to_string();
+        for webhook in self.webhooks.values() {
+            if webhook.events.contains(&event) {
+                if let Ok(url) = webhook.url.parse() {
+                    webhooks::deliver(url, body.clone());
+                }
+            }
+        }
+    }
 
-impl AppState {
-    fn initialize() -> AppState {
-        let database = Self::load_database().unwrap_or_default();
-        AppState { database }
+    fn notify_task_created(&self, project_id: usize, task_id: usize, title: &str) {
+        self.notify_webhooks(
+            WebhookEvent::TaskCreated,
+            json!({ "project_id": project_id, "task_id": task_id, "title": title }),
+        );
     }
 
-    fn load_database() -> Option<Database> {
-        File::open(Self::get_database_path())
-            .ok()
-            .and_then(|file| serde_json::from_reader(file).ok())
+    fn notify_state_change(&self, project_id: usize, change: &AppliedStateChange) {
+        self.notify_webhooks(
+            WebhookEvent::StateChange,
+            json!({
+                "project_id": project_id,
+                "task_id": change.task_id,
+                "from": change.from,
+                "to": change.to,
+                "version": change.version,
+            }),
+        );
     }
 
-    fn flush(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let database_path = Self::get_database_path();
-        let dirname = database_path
-            .parent()
-            .expect("Expected path to be absolute");
-        std::fs::create_dir_all(dirname)?;
-        serde_json::to_writer_pretty(File::create(database_path)?, &self.database)?;
-        Ok(())
+    fn record_history(
+        &mut self,
+        kind: HistoryEventKind,
+        project_id: usize,
+        task_id: Option<usize>,
+        name: &str,
+    ) {
+        self.history.push(HistoryEvent {
+            timestamp: chrono::Utc::now(),
+            kind,
+            project_id,
+            task_id,
+            name: name.to_owned(),
+        });
+    }
+
+    // Adds a single project from outside the database, always under a fresh ID
+    fn import_project(
+        &mut self,
+        mut project: Project,
+        renumber_tasks: bool,
+    ) -> Result<usize, ApiError> {
+        let errors = project.validate_external();
+        if !errors.is_empty() {
+            return Err(ApiError::InvalidImport(errors));
+        }
+        if renumber_tasks {
+            project.renumber_tasks();
+        }
+        let max_task_id = project.tasks.iter().map(|task| task.id + 1).max();
+        project.next_task_id = project.next_task_id.max(max_task_id.unwrap_or(0));
+        project.tasks.iter_mut().for_each(Task::backfill_log_ids);
+        let id = self.next_project_id;
+        project.id = id;
+        self.next_project_id += 1;
+        self.record_history(HistoryEventKind::ProjectImported, id, None, &project.name);
+        self.projects.insert(id, project);
+        Ok(id)
+    }
+
+    // Adds projects from outside the database. A project keeps its ID unless that is already
+    // taken, in which case it gets a fresh one. Nothing is changed unless `commit` is set and
+    // every project is valid, so the same planning runs for dry runs and real imports.
+    fn import_projects(&mut self, projects: Vec<Project>, commit: bool) -> ImportReport {
+        let mut next_project_id = self.next_project_id;
+        let mut taken = self.projects.keys().copied().collect::<HashSet<_>>();
+        let mut report = ImportReport::default();
+        let mut planned = Vec::new();
+        for mut project in projects {
+            for error in project.validate_external() {
+                report
+                    .errors
+                    .push(format!("Project {}: {}", project.id, error));
+            }
+            let original_id = project.id;
+            if !taken.insert(project.id) {
+                while taken.contains(&next_project_id) {
+                    next_project_id += 1;
+                }
+                project.id = next_project_id;
+                taken.insert(project.id);
+            }
+            next_project_id = next_project_id.max(project.id + 1);
+            let max_task_id = project.tasks.iter().map(|task| task.id + 1).max();
+            project.next_task_id = project.next_task_id.max(max_task_id.unwrap_or(0));
+            project.tasks.iter_mut().for_each(Task::backfill_log_ids);
+            report.projects.push(ImportedProject {
+                original_id,
+                new_id: project.id,
+                name: project.name.clone(),
+                task_count: project.tasks.len(),
+            });
+            planned.push(project);
+        }
+        if commit && report.errors.is_empty() {
+            for project in planned {
+                self.record_history(
+                    HistoryEventKind::ProjectImported,
+                    project.id,
+                    None,
+                    &project.name,
+                );
+                self.projects.insert(project.id, project);
+            }
+            self.next_project_id = next_project_id;
+            report.applied = true;
+        }
+        report
     }
 
-    fn get_database_path() -> PathBuf {
-        let mut data_dir = dirs::data_dir().expect("Could not get data directory");
-        data_dir.push("btasks");
-        data_dir.push("database.json");
-        data_dir
+    // Returns whether the project existed before removal
+    fn remove_project(&mut self, project_id: usize) -> bool {
+        self.share_tokens
+            .retain(|_, share_token| share_token.project_id != project_id);
+        match self.projects.remove(&project_id) {
+            Some(project) => {
+                self.record_history(
+                    HistoryEventKind::ProjectDeleted,
+                    project_id,
+                    None,
+                    &project.name,
+                );
+                true
+            }
+            None => false,
+        }
     }
 }
 
 #[derive(Serialize, Debug)]
-struct ProjectPeek {
-    id: usize,
+struct ImportedProject {
+    original_id: usize,
+    new_id: usize,
     name: String,
+    task_count: usize,
 }
 
-async fn list_projects(
-    _request: Request<Body>,
-    app_state: Arc<Mutex<AppState>>,
-) -> Result<Response<Body>, Box<dyn std::error::Error>> {
-    let app = app_state.lock().unwrap();
-    let projects = app
-        .database
-        .projects
-        .iter()
-        .map(|project| ProjectPeek {
-            id: project.id,
-            name: project.name.clone(),
-        })
-        .collect::<Vec<_>>();
-    Ok(Response::new(Body::from(
-        json!({ "projects": projects }).to_string(),
-    )))
+#[derive(Default, Serialize, Debug)]
+struct ImportReport {
+    applied: bool,
+    projects: Vec<ImportedProject>,
+    errors: Vec<String>,
 }
 
-#[derive(Deserialize, Debug)]
-struct ProjectDetailsRequest {
-    project_id: usize,
+struct Config {
+    port: u16,
+    // Address to listen on. Loopback unless set otherwise, so only local clients can connect.
+    host: IpAddr,
+    // Maximum number of entries kept in each task's log. Unlimited if not set.
+    max_log_length: Option<usize>,
+    // Shut down after going this long without a request. Run forever if not set.
+    idle_timeout: Option<Duration>,
+    // Number of changes to the database after which it is flushed to disk straight away, even if
+    // the flush interval hasn't passed. If not set, every change is flushed unless there is a
+    // flush interval.
+    flush_threshold: Option<usize>,
+    // Number of flushes in a row which may fail before flush_failure_action is taken. Nothing is
+    // done about failing flushes if not set.
+    flush_failure_limit: Option<usize>,
+    flush_failure_action: FlushFailureAction,
+    // Minimum time from the end of one flush to the start of the next. Flushes which would come
+    // sooner are held back until it has passed. No minimum if not set.
+    flush_cooldown: Option<Duration>,
+    // If set, handlers never write the database themselves. Changes are written by
+    // flush_deferred_changes instead, at most once per interval.
+    flush_interval: Option<Duration>,
+    // States in which no more work is expected on a task
+    terminal_states: HashSet<State>,
+    // Archive tasks which have been in a terminal state for this long. Never archive tasks
+    // automatically if not set.
+    auto_archive_after: Option<Duration>,
+    // Where the database is kept, from the command line. See AppState::resolve_database_path.
+    database_path: Option<PathBuf>,
+    // Sent as Access-Control-Allow-Origin, so browser clients served from there can use the API
+    cors_origin: header::HeaderValue,
+    // Largest request body accepted, in bytes, before and after decompression
+    max_body_bytes: usize,
 }
 
-#[derive(Serialize, Debug)]
-struct TaskPeek {
-    id: usize,
-    title: String,
-    state: State,
-}
+impl Config {
+    // What the server runs with when no options are given
+    fn new(port: u16) -> Config {
+        Config {
+            port,
+            host: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            max_log_length: None,
+            idle_timeout: None,
+            flush_threshold: None,
+            flush_failure_limit: None,
+            flush_failure_action: FlushFailureAction::ReadOnly,
+            flush_cooldown: None,
+            flush_interval: None,
+            terminal_states: [State::Done, State::Cancelled].into_iter().collect(),
+            auto_archive_after: None,
+            database_path: None,
+            cors_origin: header::HeaderValue::from_static("*"),
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+        }
+    }
 
-async fn project_details(
-    request: Request<Body>,
-    app_state: Arc<Mutex<AppState>>,
-) -> Result<Response<Body>, Box<dyn std::error::Error>> {
-    let full_body = hyper::body::to_bytes(request.into_body()).await?;
-    let request = serde_json::from_slice::<ProjectDetailsRequest>(&full_body)?;
-    let app = app_state.lock().unwrap();
-    let project = app.database.find_project_by_id(request.project_id)?;
-    let tasks = project
-        .tasks
-        .iter()
-        .map(|task| TaskPeek {
-            id: task.id,
-            title: task.title.clone(),
-            state: task.state,
-        })
-        .collect::<Vec<_>>();
-    Ok(Response::new(Body::from(
-        json!({
-            "name": project.name.clone(),
-            "id": project.id,
-            "description": project.description.clone(),
-            "tasks": tasks
-        })
-        .to_string(),
-    )))
+    fn is_terminal(&self, state: State) -> bool {
+        self.terminal_states.contains(&state)
+    }
 }
 
-#[derive(Deserialize, Debug)]
-struct PostProjectCreateRequest {
-    name: String,
-    description: String,
+// What to do once flushing the database has failed too many times in a row
+enum FlushFailureAction {
+    // Reject changes to the database until a flush succeeds again
+    ReadOnly,
+    // POST an alert to the given URL, and keep accepting changes
+    Alert(hyper::Uri),
 }
 
-async fn post_project_create(
-    request: Request<Body>,
-    app_state: Arc<Mutex<AppState>>,
-) -> Result<Response<Body>, Box<dyn std::error::Error>> {
-    let full_body = hyper::body::to_bytes(request.into_body()).await?;
-    let request = serde_json::from_slice::<PostProjectCreateRequest>(&full_body)?;
-    let mut app = app_state.lock().unwrap();
-    let project_id = app
-        .database
-        .create_project(request.name, request.description);
-    app.flush()?;
-    Ok(Response::new(Body::from(
-        json!({ "project_id": project_id }).to_string(),
-    )))
-}
+const MAX_SNAPSHOT_NAME_LENGTH: usize = 64;
 
-#[derive(Deserialize, Debug)]
-struct PostProjectDeleteRequest {
-    project_id: usize,
+struct AppState {
+    database: Database,
+    config: Config,
+    database_path: PathBuf,
+    // In its own lock, so every request can update it while only holding the state for reading
+    last_request: Mutex<Instant>,
+    // Changes made since the database was last flushed
+    pending_mutations: usize,
+    consecutive_flush_failures: usize,
+    last_flush: Option<Instant>,
+    last_successful_flush: Option<chrono::DateTime<chrono::Utc>>,
+    // Whether a flush is being held back by the cooldown, or left to the flush interval
+    flush_deferred: bool,
+    // Wakes up flush_deferred_changes when a flush gets held back
+    flush_waker: Arc<tokio::sync::Notify>,
+    // Held by flush_database from reading the database until its file is in place, so flushes
+    // reach the disk in the order they read it. Snapshots are written under it too, since writes to
+    // the same file share its temporary file.
+    flush_lock: Arc<tokio::sync::Mutex<()>>,
+    // Feeds GET /events. Taken on shutdown, which ends every open event stream so the server can
+    // stop.
+    change_events: Option<tokio::sync::broadcast::Sender<ChangeEvent>>,
 }
 
-async fn post_project_delete(
-    request: Request<Body>,
-    app_state: Arc<Mutex<AppState>>,
-) -> Result<Response<Body>, Box<dyn std::error::Error>> {
-    let full_body = hyper::body::to_bytes(request.into_body()).await?;
-    let request = serde_json::from_slice::<PostProjectDeleteRequest>(&full_body)?;
-    let mut app = app_state.lock().unwrap();
-    app.database.remove_project(request.project_id)?;
-    app.flush()?;
-    Ok(Response::new(Body::from(
-        json!({"status": 200, "description": "OK"}).to_string(),
-    )))
-}
+impl AppState {
+    async fn initialize(config: Config) -> AppState {
+        let database_path = Self::resolve_database_path(&config);
+        let database = Self::load_database(&database_path)
+            .await
+            .unwrap_or_default();
+        AppState {
+            database,
+            config,
+            database_path,
+            last_request: Mutex::new(Instant::now()),
+            pending_mutations: 0,
+            consecutive_flush_failures: 0,
+            last_flush: None,
+            last_successful_flush: None,
+            flush_deferred: false,
+            flush_waker: Arc::new(tokio::sync::Notify::new()),
+            flush_lock: Arc::new(tokio::sync::Mutex::new(())),
+            change_events: Some(tokio::sync::broadcast::channel(CHANGE_EVENT_CAPACITY).0),
+        }
+    }
 
-#[derive(Deserialize, Debug)]
-struct PostProjectNameRequest {
-    project_id: usize,
-    name: String,
-}
+    fn publish_change(&self, event: ChangeEvent) {
+        if let Some(change_events) = &self.change_events {
+            // Fails only when no client is listening
+            let _ = change_events.send(event);
+        }
+    }
 
-async fn post_project_name(
-    request: Request<Body>,
-    app_state: Arc<Mutex<AppState>>,
-) -> Result<Response<Body>, Box<dyn std::error::Error>> {
-    let full_body = hyper::body::to_bytes(request.into_body()).await?;
-    let request = serde_json::from_slice::<PostProjectNameRequest>(&full_body)?;
-    let mut app = app_state.lock().unwrap();
-    let project = app.database.find_project_by_id_mut(request.project_id)?;
-    project.name = request.name;
-    app.flush()?;
-    Ok(Response::new(Body::from(
-        json!({"status": 200, "description": "OK"}).to_string(),
-    )))
-}
+    async fn load_database(path: &Path) -> Option<Database> {
+        let temp_path = Self::get_temp_path(path);
+        // Left behind by a flush which was interrupted before renaming its temporary file over a
+        // database file which wasn't there yet
+        if tokio::fs::metadata(path).await.is_err() && tokio::fs::metadata(&temp_path).await.is_ok()
+        {
+            match Self::read_database(&temp_path).await {
+                Ok(database) => {
+                    eprintln!("* Recovered database from {}", temp_path.display());
+                    if let Err(error) = tokio::fs::rename(&temp_path, path).await {
+                        eprintln!(
+                            "ERROR: Could not move recovered database into place: {}",
+                            error
+                        );
+                    }
+                    return Some(database);
+                }
+                Err(error) => eprintln!(
+                    "ERROR: Could not recover database from {}: {}",
+                    temp_path.display(),
+                    error
+                ),
+            }
+        }
+        Self::read_database(path).await.ok()
+    }
 
-#[derive(Deserialize, Debug)]
-struct PostProjectDescriptionRequest {
-    project_id: usize,
-    description: String,
-}
+    // tokio::fs does the reading on the blocking threads, so the async worker threads never wait
+    // on the disk
+    async fn read_database(path: &Path) -> Result<Database, Box<dyn std::error::Error>> {
+        let contents = tokio::fs::read(path).await?;
+        let mut database: Database = serde_json::from_slice(&contents)?;
+        for project in database.projects.values_mut() {
+            project.reindex_tasks();
+            project.tasks.iter_mut().for_each(Task::backfill_log_ids);
+        }
+        Ok(database)
+    }
+
+    // Called by handlers after changing the database, exactly once per request which changed it.
+    // Bumps the revision. Handlers hold the state lock, so the database isn't written here, but by
+    // flush_if_due once the handler is done.
+    fn record_mutation(&mut self) {
+        self.database.revision += 1;
+        self.pending_mutations += 1;
+        // When flush_deferred_changes is running, every change is handed to it, so none is left
+        // unwritten even if flush_if_due doesn't flush
+        if self.min_flush_gap().is_some() && !self.flush_deferred {
+            self.flush_deferred = true;
+            self.flush_waker.notify_one();
+        }
+    }
+
+    // Whether enough changes have piled up to write them straight away
+    fn flush_due(&self) -> bool {
+        self.pending_mutations > 0 && self.flush_threshold_reached() && !self.in_flush_cooldown()
+    }
+
+    fn flush_threshold_reached(&self) -> bool {
+        match self.config.flush_threshold {
+            Some(threshold) => self.pending_mutations >= threshold,
+            None => self.config.flush_interval.is_none(),
+        }
+    }
+
+    // Archives every task which has been in a terminal state for at least the given age. Tasks
+    // whose log doesn't say when they got to their current state are left alone. Returns how many
+    // tasks were archived.
+    fn archive_finished_tasks(&mut self, age: chrono::Duration) -> usize {
+        let archive_before = chrono::Utc::now() - age;
+        let mut archived = Vec::new();
+        for project in self.database.projects.values_mut() {
+            for task in &mut project.tasks {
+                let finished = !task.archived
+                    && self.config.is_terminal(task.state)
+                    && metrics::entered_current_state(task)
+                        .is_some_and(|since| since <= archive_before);
+                if finished {
+                    task.archived = true;
+                    task.version += 1;
+                    archived.push((project.id, task.id, task.title.clone()));
+                }
+            }
+        }
+        for (project_id, task_id, title) in &archived {
+            self.database.record_history(
+                HistoryEventKind::TaskArchived,
+                *project_id,
+                Some(*task_id),
+                title,
+            );
+        }
+        archived.len()
+    }
+
+    // The longer of the cooldown and the flush interval, if either is set
+    fn min_flush_gap(&self) -> Option<Duration> {
+        self.config.flush_cooldown.max(self.config.flush_interval)
+    }
+
+    // Only the cooldown holds back a flush for reaching the threshold. The flush interval doesn't.
+    fn in_flush_cooldown(&self) -> bool {
+        match (self.config.flush_cooldown, self.last_flush) {
+            (Some(cooldown), Some(last_flush)) => last_flush.elapsed() < cooldown,
+            _ => false,
+        }
+    }
+
+    // When the flush which is being held back may go ahead. Straight away if nothing has been
+    // flushed yet.
+    fn deferred_flush_due(&self) -> Option<Instant> {
+        let gap = self.min_flush_gap()?;
+        if !self.flush_deferred {
+            return None;
+        }
+        Some(
+            self.last_flush
+                .map_or_else(Instant::now, |last_flush| last_flush + gap),
+        )
+    }
+
+    // Done under the state lock, so the database doesn't change while it's being serialized. The
+    // file is written after the lock is released.
+    fn serialize_database(&self) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec_pretty(&self.database)
+    }
+
+    // Bookkeeping for a flush which wrote out the given number of pending changes, or failed to
+    fn finish_flush(
+        &mut self,
+        flushed_mutations: usize,
+        started: Instant,
+        written: Result<(), Box<dyn std::error::Error>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        prometheus::SERVER_METRICS.record_flush(started.elapsed(), written.is_ok());
+        // Measured from the end of the write, so a slow disk gets longer gaps between writes
+        self.last_flush = Some(Instant::now());
+        if let Err(error) = written {
+            self.consecutive_flush_failures += 1;
+            eprintln!(
+                "ERROR: Could not flush database ({} failures in a row): {}",
+                self.consecutive_flush_failures, error
+            );
+            if Some(self.consecutive_flush_failures) == self.config.flush_failure_limit {
+                if let FlushFailureAction::Alert(webhook) = &self.config.flush_failure_action {
+                    send_flush_failure_alert(
+                        webhook.clone(),
+                        self.consecutive_flush_failures,
+                        error.to_string(),
+                    );
+                }
+            }
+            return Err(error);
+        }
+        // Changes made while the file was being written weren't in it, and are still pending
+        self.pending_mutations -= flushed_mutations;
+        self.last_successful_flush = Some(chrono::Utc::now());
+        self.consecutive_flush_failures = 0;
+        self.flush_deferred = self.pending_mutations > 0 && self.min_flush_gap().is_some();
+        if self.flush_deferred {
+            self.flush_waker.notify_one();
+        }
+        Ok(())
+    }
+
+    fn is_read_only(&self) -> bool {
+        matches!(
+            self.config.flush_failure_action,
+            FlushFailureAction::ReadOnly
+        ) && self
+            .config
+            .flush_failure_limit
+            .is_some_and(|limit| self.consecutive_flush_failures >= limit)
+    }
+
+    // The --database flag wins over the BTASKS_DB_PATH environment variable, which wins over the
+    // default under the user's data directory. Relative paths are taken from the directory the
+    // server was started in.
+    fn resolve_database_path(config: &Config) -> PathBuf {
+        let path = config
+            .database_path
+            .clone()
+            .or_else(|| std::env::var_os("BTASKS_DB_PATH").map(PathBuf::from))
+            .unwrap_or_else(|| {
+                let mut data_dir = dirs::data_dir().expect("Could not get data directory");
+                data_dir.push("btasks");
+                data_dir.push("database.json");
+                data_dir
+            });
+        if path.is_absolute() {
+            path
+        } else {
+            std::env::current_dir()
+                .expect("Could not get current directory")
+                .join(path)
+        }
+    }
+
+    // In the same directory as the given path, so renaming it over the path stays atomic
+    fn get_temp_path(path: &Path) -> PathBuf {
+        let mut temp_path = path.to_owned();
+        temp_path.set_extension("json.tmp");
+        temp_path
+    }
+
+    fn get_snapshot_dir(&self) -> PathBuf {
+        let mut snapshot_dir = self.database_path.clone();
+        snapshot_dir.set_file_name("snapshots");
+        snapshot_dir
+    }
+
+    // Snapshot names end up in file names, so they're limited to characters which can't escape the
+    // snapshot directory
+    fn get_snapshot_path(&self, name: &str) -> Result<PathBuf, ApiError> {
+        let valid = !name.is_empty()
+            && name.len() <= MAX_SNAPSHOT_NAME_LENGTH
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(ApiError::InvalidValue(format!(
+                "Invalid snapshot name `{}`, expected up to {} letters, digits, '-' or '_'",
+                name, MAX_SNAPSHOT_NAME_LENGTH
+            )));
+        }
+        let mut snapshot_path = self.get_snapshot_dir();
+        snapshot_path.push(format!("{}.json", name));
+        Ok(snapshot_path)
+    }
+}
+
+// Every request goes through the one lock, so time spent waiting on it gets its own span. Handlers
+// which only look at the state take it for reading, so they can run alongside each other. Anything
+// which changes the database, or may flush it, takes it for writing.
+fn read_state(app_state: &RwLock<AppState>) -> RwLockReadGuard<'_, AppState> {
+    tracing::debug_span!("lock", write = false).in_scope(|| app_state.read().unwrap())
+}
+
+fn write_state(app_state: &RwLock<AppState>) -> RwLockWriteGuard<'_, AppState> {
+    tracing::debug_span!("lock", write = true).in_scope(|| app_state.write().unwrap())
+}
+
+// Writes the database to disk without holding the state lock while the disk is busy. Only
+// serializing it needs the lock, and then only for reading.
+async fn flush_database(
+    app_state: &Arc<RwLock<AppState>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let flush_lock = read_state(app_state).flush_lock.clone();
+    let _flushing = flush_lock.lock().await;
+    let started = Instant::now();
+    let (database_path, flushed_mutations, contents) = {
+        let app = read_state(app_state);
+        (
+            app.database_path.clone(),
+            app.pending_mutations,
+            app.serialize_database(),
+        )
+    };
+    let written = match contents {
+        Ok(contents) => {
+            write_database_file(database_path, contents)
+                .instrument(tracing::info_span!("flush", pending = flushed_mutations))
+                .await
+        }
+        Err(error) => Err(error.into()),
+    };
+    write_state(app_state).finish_flush(flushed_mutations, started, written)
+}
+
+// Called once a change has been made and the state lock released again
+async fn flush_if_due(app_state: &Arc<RwLock<AppState>>) -> Result<(), Box<dyn std::error::Error>> {
+    if !read_state(app_state).flush_due() {
+        return Ok(());
+    }
+    flush_database(app_state).await
+}
+
+// Called before handling a request which changes the database. While read-only, each such request
+// retries the flush first, and is only let through if that works.
+async fn ensure_writable(app_state: &Arc<RwLock<AppState>>) -> Result<(), ApiError> {
+    if read_state(app_state).is_read_only() && flush_database(app_state).await.is_err() {
+        let failures = read_state(app_state).consecutive_flush_failures;
+        return Err(ApiError::ReadOnly(failures));
+    }
+    Ok(())
+}
+
+// Does the file I/O on tokio's blocking threads, so the async worker threads never wait on the
+// disk. The contents go to a temporary file which is then renamed over the real one, so a crash
+// part way through leaves the old file whole.
+async fn write_database_file(
+    path: PathBuf,
+    contents: Vec<u8>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    tokio::task::spawn_blocking(move || {
+        let dirname = path.parent().expect("Expected path to be absolute");
+        let temp_path = AppState::get_temp_path(&path);
+        std::fs::create_dir_all(dirname)?;
+        let mut file = std::fs::File::create(&temp_path)?;
+        file.write_all(&contents)?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, &path)
+    })
+    .await??;
+    Ok(())
+}
+
+// Sent in the background, so a slow or unreachable webhook doesn't hold up the request which
+// triggered the alert
+fn send_flush_failure_alert(webhook: hyper::Uri, consecutive_failures: usize, error: String) {
+    tokio::spawn(async move {
+        let body = json!({
+            "event": "flush_failing",
+            "consecutive_failures": consecutive_failures,
+            "error": error,
+        })
+        .to_string();
+        let request = Request::post(webhook)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .expect("Failed to build request");
+        if let Err(error) = hyper::Client::new().request(request).await {
+            eprintln!("ERROR: Could not send flush failure alert: {}", error);
+        }
+    });
+}
+
+const MSGPACK_MIME: &str = "application/msgpack";
+
+fn header_contains(request: &Request<Body>, header: header::HeaderName, value: &str) -> bool {
+    request
+        .headers()
+        .get(header)
+        .and_then(|header| header.to_str().ok())
+        .is_some_and(|header| header.contains(value))
+}
+
+// Whether an If-None-Match header among the request's headers matches the given entity tag, which
+// is quoted
+fn if_none_match(headers: &header::HeaderMap, etag: &str) -> bool {
+    let header = match headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|header| header.to_str().ok())
+    {
+        Some(header) => header,
+        None => return false,
+    };
+    header.split(',').map(str::trim).any(|tag| {
+        // Weak comparison, as If-None-Match calls for
+        tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag
+    })
+}
+
+// Errors which should be reported with a specific status and error code, rather than a generic
+// 500
+#[derive(Debug)]
+enum ApiError {
+    MissingField(String),
+    InvalidBody(String),
+    InvalidImport(Vec<String>),
+    InvalidValue(String),
+    BodyTooLarge(usize),
+    UnknownTimeZone(String),
+    ReadOnly(usize),
+    Forbidden(String),
+    // The project, task, log entry or saved query which the request refers to doesn't exist
+    ProjectNotFound(usize),
+    TaskNotFound(usize),
+    LogEntryNotFound(usize),
+    QueryNotFound(String),
+    WebhookNotFound(usize),
+    // Marking a task done while these tasks it depends on aren't finished
+    UnfinishedDependencies(Vec<usize>),
+    // Dependencies which go round in a cycle, described by what ran into it
+    DependencyCycle(&'static str, Vec<usize>),
+}
+
+impl ApiError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ApiError::MissingField(_)
+            | ApiError::InvalidBody(_)
+            | ApiError::InvalidImport(_)
+            | ApiError::InvalidValue(_)
+            | ApiError::UnknownTimeZone(_) => StatusCode::BAD_REQUEST,
+            ApiError::ReadOnly(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::ProjectNotFound(_)
+            | ApiError::TaskNotFound(_)
+            | ApiError::LogEntryNotFound(_)
+            | ApiError::QueryNotFound(_)
+            | ApiError::WebhookNotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::UnfinishedDependencies(_) | ApiError::DependencyCycle(..) => {
+                StatusCode::CONFLICT
+            }
+            ApiError::BodyTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            ApiError::MissingField(_) => "MISSING_FIELD",
+            ApiError::InvalidBody(_) => "INVALID_BODY",
+            ApiError::InvalidImport(_) => "INVALID_IMPORT",
+            ApiError::InvalidValue(_) => "INVALID_VALUE",
+            ApiError::BodyTooLarge(_) => "BODY_TOO_LARGE",
+            ApiError::UnknownTimeZone(_) => "UNKNOWN_TIME_ZONE",
+            ApiError::ReadOnly(_) => "READ_ONLY",
+            ApiError::Forbidden(_) => "FORBIDDEN",
+            ApiError::ProjectNotFound(_) => "PROJECT_NOT_FOUND",
+            ApiError::TaskNotFound(_) => "TASK_NOT_FOUND",
+            ApiError::LogEntryNotFound(_) => "LOG_ENTRY_NOT_FOUND",
+            ApiError::QueryNotFound(_) => "QUERY_NOT_FOUND",
+            ApiError::WebhookNotFound(_) => "WEBHOOK_NOT_FOUND",
+            ApiError::UnfinishedDependencies(_) => "UNFINISHED_DEPENDENCIES",
+            ApiError::DependencyCycle(..) => "CYCLE_DETECTED",
+        }
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ApiError::MissingField(field) => write!(f, "Missing required field `{}`", field),
+            ApiError::InvalidBody(message) => write!(f, "Invalid request body: {}", message),
+            ApiError::InvalidImport(errors) => write!(f, "Invalid import: {}", errors.join("; ")),
+            ApiError::InvalidValue(message) => write!(f, "{}", message),
+            ApiError::BodyTooLarge(limit) => {
+                write!(
+                    f,
+                    "Request body is larger than the limit of {} bytes",
+                    limit
+                )
+            }
+            ApiError::UnknownTimeZone(name) => write!(f, "Unknown time zone: {}", name),
+            ApiError::Forbidden(message) => write!(f, "{}", message),
+            ApiError::ProjectNotFound(id) => write!(f, "Could not find project with ID: {}", id),
+            ApiError::TaskNotFound(id) => write!(f, "Could not find task with ID: {}", id),
+            ApiError::LogEntryNotFound(id) => write!(f, "Could not find log entry with ID: {}", id),
+            ApiError::QueryNotFound(name) => write!(f, "Could not find saved query: {}", name),
+            ApiError::WebhookNotFound(id) => write!(f, "Could not find webhook with ID: {}", id),
+            ApiError::UnfinishedDependencies(task_ids) => write!(
+                f,
+                "Task depends on unfinished tasks: {}",
+                task_ids
+                    .iter()
+                    .map(|id| id.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            ApiError::DependencyCycle(problem, task_ids) => write!(
+                f,
+                "{}: {}",
+                problem,
+                task_ids
+                    .iter()
+                    .map(|id| id.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" -> ")
+            ),
+            ApiError::ReadOnly(failures) => write!(
+                f,
+                "Server is read-only after failing to save the database {} times in a row",
+                failures
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+// Both serde_json and rmp_serde report missing fields with serde's standard message, of the form
+// "missing field `name`", so pick the field name out of that
+fn classify_body_error(error: impl std::error::Error) -> Box<dyn std::error::Error> {
+    let message = error.to_string();
+    let field = message
+        .strip_prefix("missing field `")
+        .and_then(|rest| rest.split('`').next());
+    match field {
+        Some(field) => Box::new(ApiError::MissingField(field.to_owned())),
+        None => Box::new(ApiError::InvalidBody(message)),
+    }
+}
+
+fn decode_body<T: DeserializeOwned>(
+    full_body: &[u8],
+    is_msgpack: bool,
+) -> Result<T, Box<dyn std::error::Error>> {
+    if is_msgpack {
+        rmp_serde::from_slice(full_body).map_err(classify_body_error)
+    } else {
+        serde_json::from_slice(full_body).map_err(classify_body_error)
+    }
+}
+
+const DEFAULT_MAX_BODY_BYTES: usize = 1 << 20;
+
+// Carries Config::max_body_bytes in the request's extensions, for read_body
+#[derive(Clone, Copy)]
+struct BodyLimit(usize);
+
+// Reads the full request body, decompressing it if the client sent it gzipped. Also returns
+// whether the body is MessagePack.
+async fn read_body(request: Request<Body>) -> Result<(Vec<u8>, bool), Box<dyn std::error::Error>> {
+    let is_msgpack = header_contains(&request, header::CONTENT_TYPE, MSGPACK_MIME);
+    let is_gzip = header_contains(&request, header::CONTENT_ENCODING, "gzip");
+    let limit = request
+        .extensions()
+        .get::<BodyLimit>()
+        .map_or(DEFAULT_MAX_BODY_BYTES, |limit| limit.0);
+    let content_length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok());
+    if matches!(content_length, Some(length) if length > limit as u64) {
+        return Err(ApiError::BodyTooLarge(limit).into());
+    }
+    // Chunked bodies don't say how long they are, so stop reading them as soon as they go over
+    let mut body = request.into_body();
+    let mut full_body = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if full_body.len() + chunk.len() > limit {
+            return Err(ApiError::BodyTooLarge(limit).into());
+        }
+        full_body.extend_from_slice(&chunk);
+    }
+    if !is_gzip {
+        return Ok((full_body, is_msgpack));
+    }
+    // Read at most one byte past the limit, so that a small body which inflates into something
+    // huge is caught without inflating all of it
+    let mut decompressed = Vec::new();
+    flate2::read::GzDecoder::new(&full_body[..])
+        .take(limit as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|error| ApiError::InvalidBody(format!("Could not decompress body: {}", error)))?;
+    if decompressed.len() > limit {
+        return Err(ApiError::BodyTooLarge(limit).into());
+    }
+    Ok((decompressed, is_msgpack))
+}
+
+// GET requests may give the fields of their body as query parameters instead, e.g.
+// /task?project_id=0&task_id=3, which is what Location headers point at. A share token alone
+// doesn't count, since it isn't one of the endpoint's fields.
+fn query_params(request: &Request<Body>) -> Option<&str> {
+    if request.method() != Method::GET {
+        return None;
+    }
+    let query = request.uri().query()?;
+    query
+        .split('&')
+        .any(|param| {
+            let name = param.split('=').next().unwrap_or(param);
+            !name.is_empty() && name != SHARE_TOKEN_PARAM
+        })
+        .then_some(query)
+}
+
+fn decode_query<T: DeserializeOwned>(query: &str) -> Result<T, Box<dyn std::error::Error>> {
+    query::from_query(query).map_err(classify_body_error)
+}
+
+// Reads the full request body, and deserializes it from MessagePack if the client says it sent
+// that, or otherwise from JSON. GET requests with query parameters are read from those instead.
+async fn parse_body<T: DeserializeOwned>(
+    request: Request<Body>,
+) -> Result<T, Box<dyn std::error::Error>> {
+    if let Some(query) = query_params(&request) {
+        return decode_query(query);
+    }
+    let (full_body, is_msgpack) = read_body(request).await?;
+    decode_body(&full_body, is_msgpack)
+}
+
+// Like parse_body, but an empty body gives the default request
+async fn parse_optional_body<T: DeserializeOwned + Default>(
+    request: Request<Body>,
+) -> Result<T, Box<dyn std::error::Error>> {
+    if let Some(query) = query_params(&request) {
+        return decode_query(query);
+    }
+    let (full_body, is_msgpack) = read_body(request).await?;
+    if full_body.is_empty() {
+        Ok(T::default())
+    } else {
+        decode_body(&full_body, is_msgpack)
+    }
+}
+
+// Handlers always produce JSON. For clients which asked for MessagePack, transcode the response
+// body on the way out.
+async fn encode_msgpack(response: Response<Body>) -> Result<Response<Body>, hyper::Error> {
+    let (mut parts, body) = response.into_parts();
+    let full_body = hyper::body::to_bytes(body).await?;
+    let encoded = serde_json::from_slice::<serde_json::Value>(&full_body)
+        .ok()
+        .and_then(|value| rmp_serde::to_vec_named(&value).ok());
+    match encoded {
+        Some(encoded) => {
+            parts.headers.insert(
+                header::CONTENT_TYPE,
+                header::HeaderValue::from_static(MSGPACK_MIME),
+            );
+            Ok(Response::from_parts(parts, Body::from(encoded)))
+        }
+        None => Ok(Response::from_parts(parts, Body::from(full_body))),
+    }
+}
+
+// Fields holding unix timestamps, which get a localized copy when a client asks for a time zone
+const TIMESTAMP_FIELDS: [&str; 4] = ["timestamp", "due_date", "created_at", "edited_at"];
+
+fn parse_time_zone(name: Option<&str>) -> Result<Option<chrono_tz::Tz>, ApiError> {
+    name.map(|name| {
+        name.parse()
+            .map_err(|_| ApiError::UnknownTimeZone(name.to_owned()))
+    })
+    .transpose()
+}
+
+// Next to every timestamp field in the given response, adds a "<field>_local" field with the
+// same time as an ISO-8601 string in the given time zone
+fn localize_timestamps(value: &mut serde_json::Value, time_zone: chrono_tz::Tz) {
+    use chrono::TimeZone;
+    match value {
+        serde_json::Value::Object(object) => {
+            let mut localized = Vec::new();
+            for (key, field) in object.iter_mut() {
+                match field.as_i64() {
+                    Some(seconds) if TIMESTAMP_FIELDS.contains(&key.as_str()) => {
+                        // Timestamps too far out for chrono to represent are left unlocalized
+                        if let Some(local) = time_zone.timestamp_opt(seconds, 0).single() {
+                            localized.push((format!("{}_local", key), local.to_rfc3339()));
+                        }
+                    }
+                    _ => localize_timestamps(field, time_zone),
+                }
+            }
+            for (key, local) in localized {
+                object.insert(key, serde_json::Value::String(local));
+            }
+        }
+        serde_json::Value::Array(array) => {
+            for element in array {
+                localize_timestamps(element, time_zone);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct ProjectPeek {
+    id: usize,
+    name: String,
+    group: Option<String>,
+}
+
+#[derive(Default, Deserialize, Debug)]
+struct ListProjectsRequest {
+    #[serde(default)]
+    grouped: bool,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+async fn list_projects(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let headers = request.headers().clone();
+    let request = parse_optional_body::<ListProjectsRequest>(request).await?;
+    let limit = request.limit.unwrap_or(DEFAULT_QUERY_LIMIT);
+    check_limit(limit)?;
+    let app = read_state(&app_state);
+    // Each page, grouped or not, is a list of its own, so it needs a tag of its own too
+    let etag = format!(
+        "\"{}-{}-{}{}\"",
+        app.database.revision,
+        request.offset,
+        limit,
+        if request.grouped { "-grouped" } else { "" }
+    );
+    if if_none_match(&headers, &etag) {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(header::ETAG, etag)
+            .body(Body::empty())?);
+    }
+    let total = app.database.projects.len();
+    let projects = app
+        .database
+        .projects
+        .values()
+        .skip(request.offset)
+        .take(limit)
+        .map(|project| ProjectPeek {
+            id: project.id,
+            name: project.name.clone(),
+            group: project.group.clone(),
+        })
+        .collect::<Vec<_>>();
+    if !request.grouped {
+        return Ok(Response::builder()
+            .header(header::ETAG, etag)
+            .body(Body::from(
+                json!({ "total": total, "offset": request.offset, "projects": projects })
+                    .to_string(),
+            ))?);
+    }
+    let mut groups = BTreeMap::<String, Vec<ProjectPeek>>::new();
+    let mut ungrouped = Vec::new();
+    for project in projects {
+        match &project.group {
+            Some(group) => groups.entry(group.clone()).or_default().push(project),
+            None => ungrouped.push(project),
+        }
+    }
+    Ok(Response::builder()
+        .header(header::ETAG, etag)
+        .body(Body::from(
+            json!({
+                "total": total,
+                "offset": request.offset,
+                "groups": groups,
+                "ungrouped": ungrouped,
+            })
+            .to_string(),
+        ))?)
+}
+
+async fn revision(
+    _request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let app = read_state(&app_state);
+    Ok(Response::new(Body::from(
+        json!({ "revision": app.database.revision }).to_string(),
+    )))
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Deserialize, Debug)]
+struct ProjectDetailsRequest {
+    project_id: usize,
+    #[serde(default = "default_true")]
+    include_tasks: bool,
+    #[serde(default)]
+    include_archived: bool,
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "default_query_limit")]
+    limit: usize,
+}
+
+#[derive(Serialize, Debug)]
+struct TaskPeek {
+    id: usize,
+    title: String,
+    state: State,
+    priority: Priority,
+    #[serde(with = "chrono::serde::ts_seconds_option")]
+    due_date: Option<chrono::DateTime<chrono::Utc>>,
+    comment_count: usize,
+    color: Option<String>,
+    archived: bool,
+}
+
+impl TaskPeek {
+    fn new(task: &Task) -> TaskPeek {
+        let comment_count = task
+            .log
+            .iter()
+            .filter(|entry| matches!(entry.entry_type, LogEntryType::Comment(_)))
+            .count();
+        TaskPeek {
+            id: task.id,
+            title: task.title.clone(),
+            state: task.state,
+            priority: task.priority,
+            due_date: task.due_date,
+            comment_count,
+            color: task.color.clone(),
+            archived: task.archived,
+        }
+    }
+}
+
+async fn project_details(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<ProjectDetailsRequest>(request).await?;
+    check_limit(request.limit)?;
+    let app = read_state(&app_state);
+    let project = app.database.find_project_by_id(request.project_id)?;
+    let tasks = project
+        .tasks
+        .iter()
+        .filter(|task| request.include_archived || !task.archived);
+    if !request.include_tasks {
+        return Ok(Response::new(Body::from(
+            json!({
+                "name": project.name.clone(),
+                "id": project.id,
+                "description": project.description.clone(),
+                "created_at": project.created_at.timestamp(),
+                "task_count": tasks.count(),
+            })
+            .to_string(),
+        )));
+    }
+    let tasks = tasks.collect::<Vec<_>>();
+    let total = tasks.len();
+    let tasks = tasks
+        .into_iter()
+        .skip(request.offset)
+        .take(request.limit)
+        .map(TaskPeek::new)
+        .collect::<Vec<_>>();
+    Ok(Response::new(Body::from(
+        json!({
+            "name": project.name.clone(),
+            "id": project.id,
+            "description": project.description.clone(),
+            "created_at": project.created_at.timestamp(),
+            "total": total,
+            "offset": request.offset,
+            "tasks": tasks
+        })
+        .to_string(),
+    )))
+}
+
+#[derive(Deserialize, Debug)]
+struct ProjectFullRequest {
+    project_id: usize,
+    #[serde(default = "default_true")]
+    include_logs: bool,
+    #[serde(default)]
+    tz: Option<String>,
+}
+
+async fn project_full(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<ProjectFullRequest>(request).await?;
+    let time_zone = parse_time_zone(request.tz.as_deref())?;
+    let app = read_state(&app_state);
+    let project = app.database.find_project_by_id(request.project_id)?;
+    let mut full = serde_json::to_value(project)?;
+    if !request.include_logs {
+        if let Some(tasks) = full["tasks"].as_array_mut() {
+            for task in tasks.iter_mut().filter_map(|task| task.as_object_mut()) {
+                task.remove("log");
+            }
+        }
+    }
+    if let Some(time_zone) = time_zone {
+        localize_timestamps(&mut full, time_zone);
+    }
+    Ok(Response::new(Body::from(full.to_string())))
+}
+
+// Trims surrounding whitespace off a name or title, which must have something left after that
+fn non_empty_name(name: &str, what: &str) -> Result<String, ApiError> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(ApiError::InvalidValue(format!("{} cannot be empty", what)));
+    }
+    Ok(name.to_owned())
+}
+
+// 201 response for an endpoint which created something, pointing at it with a Location header
+fn created(
+    location: String,
+    body: serde_json::Value,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    Ok(Response::builder()
+        .status(StatusCode::CREATED)
+        .header(header::LOCATION, location)
+        .body(Body::from(body.to_string()))?)
+}
+
+fn project_location(project_id: usize) -> String {
+    format!("/project?project_id={}", project_id)
+}
+
+fn task_location(project_id: usize, task_id: usize) -> String {
+    format!("/task?project_id={}&task_id={}", project_id, task_id)
+}
+
+// Response for endpoints which update a single field. Says what the field changed from and to, so
+// clients can update their own copy without fetching it again. `changes` is left empty when the
+// value stayed the same. Tasks also report their new version.
+fn field_changed<T: Serialize>(
+    field: &str,
+    from: &T,
+    to: &T,
+    version: Option<u64>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let from = serde_json::to_value(from)?;
+    let to = serde_json::to_value(to)?;
+    let mut changes = serde_json::Map::new();
+    if from != to {
+        changes.insert(field.to_owned(), json!({ "from": from, "to": to }));
+    }
+    let mut response = json!({"status": 200, "description": "OK", "changes": changes});
+    if let Some(version) = version {
+        response["version"] = json!(version);
+    }
+    Ok(Response::new(Body::from(response.to_string())))
+}
+
+#[derive(Deserialize, Debug)]
+struct PostProjectCreateRequest {
+    name: String,
+    description: String,
+}
+
+async fn post_project_create(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostProjectCreateRequest>(request).await?;
+    let name = non_empty_name(&request.name, "Project name")?;
+    let mut app = write_state(&app_state);
+    let project_id = app.database.create_project(name, request.description);
+    app.record_mutation();
+    created(
+        project_location(project_id),
+        json!({ "project_id": project_id }),
+    )
+}
+
+#[derive(Deserialize, Debug)]
+struct PostProjectDuplicateRequest {
+    project_id: usize,
+    name: Option<String>,
+}
+
+async fn post_project_duplicate(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostProjectDuplicateRequest>(request).await?;
+    let name = request
+        .name
+        .map(|name| non_empty_name(&name, "Project name"))
+        .transpose()?;
+    let mut app = write_state(&app_state);
+    let project_id = app.database.duplicate_project(request.project_id, name)?;
+    app.record_mutation();
+    created(
+        project_location(project_id),
+        json!({ "project_id": project_id }),
+    )
+}
+
+#[derive(Deserialize, Debug)]
+struct PostProjectShareRequest {
+    project_id: usize,
+    #[serde(default)]
+    expires_in_seconds: Option<i64>,
+}
+
+const SHARE_TOKEN_LENGTH: usize = 32;
+
+async fn post_project_share(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostProjectShareRequest>(request).await?;
+    let expires_at = match request.expires_in_seconds {
+        Some(seconds) if seconds <= 0 => {
+            return Err(
+                ApiError::InvalidValue("expires_in_seconds must be positive".to_owned()).into(),
+            )
+        }
+        Some(seconds) => Some(
+            chrono::Duration::try_seconds(seconds)
+                .and_then(|expires_in| chrono::Utc::now().checked_add_signed(expires_in))
+                .ok_or_else(|| {
+                    ApiError::InvalidValue(format!("expires_in_seconds is too large: {}", seconds))
+                })?,
+        ),
+        None => None,
+    };
+    let mut app = write_state(&app_state);
+    app.database.find_project_by_id(request.project_id)?;
+    let token = Alphanumeric.sample_string(&mut rand::thread_rng(), SHARE_TOKEN_LENGTH);
+    app.database.share_tokens.insert(
+        token.clone(),
+        ShareToken {
+            project_id: request.project_id,
+            expires_at,
+        },
+    );
+    app.record_mutation();
+    Ok(Response::new(Body::from(
+        json!({
+            "token": token,
+            "expires_at": expires_at.map(|expires_at| expires_at.timestamp()),
+        })
+        .to_string(),
+    )))
+}
+
+#[derive(Deserialize, Debug)]
+struct PostProjectShareRevokeRequest {
+    token: String,
+}
+
+async fn post_project_share_revoke(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostProjectShareRevokeRequest>(request).await?;
+    let mut app = write_state(&app_state);
+    let removed = app.database.share_tokens.remove(&request.token).is_some();
+    if removed {
+        app.record_mutation();
+    }
+    Ok(Response::new(Body::from(
+        json!({"status": 200, "description": "OK", "already_absent": !removed}).to_string(),
+    )))
+}
+
+#[derive(Deserialize, Debug)]
+struct PostProjectGroupRequest {
+    project_id: usize,
+    group: Option<String>,
+}
+
+async fn post_project_group(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostProjectGroupRequest>(request).await?;
+    let group = request
+        .group
+        .map(|group| non_empty_name(&group, "Group name"))
+        .transpose()?;
+    let mut app = write_state(&app_state);
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    let previous = std::mem::replace(&mut project.group, group);
+    let response = field_changed("group", &previous, &project.group, None)?;
+    app.record_mutation();
+    Ok(response)
+}
+
+#[derive(Deserialize, Debug)]
+struct PostProjectShiftDueDatesRequest {
+    project_id: usize,
+    days: i64,
+}
+
+async fn post_project_shift_due_dates(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostProjectShiftDueDatesRequest>(request).await?;
+    let out_of_range = || {
+        ApiError::InvalidValue(format!(
+            "Shifting by {} days takes due dates out of range",
+            request.days
+        ))
+    };
+    let offset = chrono::Duration::try_days(request.days).ok_or_else(out_of_range)?;
+    let mut app = write_state(&app_state);
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    // Every new due date is worked out before any task is touched, so a shift which fails leaves
+    // the project as it was
+    let mut shifted_dates = Vec::new();
+    for (index, task) in project.tasks.iter().enumerate() {
+        if let Some(due_date) = task.due_date {
+            let shifted = due_date
+                .checked_add_signed(offset)
+                .ok_or_else(out_of_range)?;
+            shifted_dates.push((index, shifted));
+        }
+    }
+    for (index, due_date) in &shifted_dates {
+        let task = &mut project.tasks[*index];
+        task.due_date = Some(*due_date);
+        task.version += 1;
+    }
+    app.record_mutation();
+    Ok(Response::new(Body::from(
+        json!({ "shifted": shifted_dates.len() }).to_string(),
+    )))
+}
+
+#[derive(Deserialize, Debug)]
+struct PostProjectDeleteRequest {
+    project_id: usize,
+}
+
+async fn post_project_delete(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostProjectDeleteRequest>(request).await?;
+    let mut app = write_state(&app_state);
+    let removed = app.database.remove_project(request.project_id);
+    if removed {
+        app.record_mutation();
+    }
+    Ok(Response::new(Body::from(
+        json!({"status": 200, "description": "OK", "already_absent": !removed}).to_string(),
+    )))
+}
+
+#[derive(Deserialize, Debug)]
+struct PostProjectNameRequest {
+    project_id: usize,
+    name: String,
+}
+
+async fn post_project_name(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostProjectNameRequest>(request).await?;
+    let name = non_empty_name(&request.name, "Project name")?;
+    let mut app = write_state(&app_state);
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    let previous = std::mem::replace(&mut project.name, name);
+    let response = field_changed("name", &previous, &project.name, None)?;
+    app.record_mutation();
+    Ok(response)
+}
+
+#[derive(Deserialize, Debug)]
+struct PostProjectDescriptionRequest {
+    project_id: usize,
+    description: String,
+}
+
+async fn post_project_description(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostProjectDescriptionRequest>(request).await?;
+    let mut app = write_state(&app_state);
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    let previous = std::mem::replace(&mut project.description, request.description);
+    let response = field_changed("description", &previous, &project.description, None)?;
+    app.record_mutation();
+    Ok(response)
+}
+
+#[derive(Deserialize, Debug)]
+struct TaskDetailsRequest {
+    project_id: usize,
+    task_id: usize,
+    #[serde(default)]
+    expand_deps: bool,
+    #[serde(default)]
+    tz: Option<String>,
+    #[serde(default)]
+    entry_type: LogFilter,
+}
+
+// Which log entries to show in a task's details
+#[derive(Deserialize, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+enum LogFilter {
+    #[default]
+    All,
+    Comment,
+    State,
+}
+
+async fn task_details(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<TaskDetailsRequest>(request).await?;
+    let time_zone = parse_time_zone(request.tz.as_deref())?;
+    let app = read_state(&app_state);
+    let project = app.database.find_project_by_id(request.project_id)?;
+    let task = project.find_task_by_id(request.task_id)?;
+    let mut details = serde_json::to_value(task)?;
+    if request.expand_deps {
+        let mut dependency_ids = task.dependencies.iter().copied().collect::<Vec<_>>();
+        dependency_ids.sort_unstable();
+        let dependencies = dependency_ids
+            .into_iter()
+            .filter_map(|id| project.find_task_by_id(id).ok())
+            .map(TaskPeek::new)
+            .collect::<Vec<_>>();
+        details["dependencies"] = serde_json::to_value(dependencies)?;
+    }
+    if request.entry_type != LogFilter::All {
+        let mut log = Vec::new();
+        for entry in &task.log {
+            match (&request.entry_type, &entry.entry_type) {
+                (LogFilter::Comment, LogEntryType::Comment(_)) => {
+                    log.push(serde_json::to_value(entry)?)
+                }
+                (LogFilter::State, LogEntryType::StateChangedTo(state)) => {
+                    let mut entry = serde_json::to_value(entry)?;
+                    entry["state"] = json!(state);
+                    log.push(entry);
+                }
+                _ => {}
+            }
+        }
+        details["log"] = serde_json::Value::Array(log);
+    }
+    if let Some(time_zone) = time_zone {
+        localize_timestamps(&mut details, time_zone);
+    }
+    Ok(Response::new(Body::from(details.to_string())))
+}
+
+#[derive(Deserialize, Debug)]
+struct TaskStateRequest {
+    project_id: usize,
+    task_id: usize,
+}
+
+async fn task_state(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<TaskStateRequest>(request).await?;
+    let app = read_state(&app_state);
+    let project = app.database.find_project_by_id(request.project_id)?;
+    let task = project.find_task_by_id(request.task_id)?;
+    Ok(Response::new(Body::from(
+        json!({ "state": task.state, "version": task.version }).to_string(),
+    )))
+}
+
+#[derive(Deserialize, Debug)]
+struct PostTaskStateChange {
+    project_id: usize,
+    task_id: usize,
+    new_state: State,
+    // Marks the task done even though some of its dependencies aren't finished
+    #[serde(default)]
+    force: Option<bool>,
+}
+
+async fn post_task_state(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskStateChange>(request).await?;
+    let mut app = write_state(&app_state);
+    let max_log_length = app.config.max_log_length;
+    if request.new_state == State::Done && request.force != Some(true) {
+        let project = app.database.find_project_by_id(request.project_id)?;
+        let task = project.find_task_by_id(request.task_id)?;
+        let mut unfinished = task
+            .dependencies
+            .iter()
+            .filter_map(|id| project.find_task_by_id(*id).ok())
+            .filter(|dependency| !app.config.is_terminal(dependency.state))
+            .map(|dependency| dependency.id)
+            .collect::<Vec<_>>();
+        if !unfinished.is_empty() {
+            unfinished.sort_unstable();
+            return Err(ApiError::UnfinishedDependencies(unfinished).into());
+        }
+    }
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    let task = project.find_task_by_id_mut(request.task_id)?;
+    task.new_log_entry(
+        LogEntryType::StateChangedTo(request.new_state),
+        max_log_length,
+    );
+    let previous = std::mem::replace(&mut task.state, request.new_state);
+    task.version += 1;
+    let change = AppliedStateChange {
+        task_id: task.id,
+        from: previous,
+        to: task.state,
+        version: task.version,
+    };
+    let response = field_changed("state", &previous, &task.state, Some(task.version))?;
+    app.record_mutation();
+    app.database
+        .notify_state_change(request.project_id, &change);
+    Ok(response)
+}
+
+#[derive(Deserialize, Debug)]
+struct StateChange {
+    task_id: usize,
+    new_state: State,
+}
+
+#[derive(Deserialize, Debug)]
+struct PostTaskStateBulkRequest {
+    project_id: usize,
+    changes: Vec<StateChange>,
+    #[serde(default)]
+    force: Option<bool>,
+}
+
+#[derive(Serialize, Debug)]
+struct AppliedStateChange {
+    task_id: usize,
+    from: State,
+    to: State,
+    version: u64,
+}
+
+// Applies every change or none of them. The checks run against the states the batch leaves tasks
+// in, so a task may be marked done along with its dependencies.
+async fn post_task_state_bulk(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskStateBulkRequest>(request).await?;
+    let mut seen = HashSet::new();
+    if let Some(repeated) = request
+        .changes
+        .iter()
+        .find(|change| !seen.insert(change.task_id))
+    {
+        return Err(ApiError::InvalidValue(format!(
+            "Task {} appears more than once in the batch",
+            repeated.task_id
+        ))
+        .into());
+    }
+    let mut app = write_state(&app_state);
+    let max_log_length = app.config.max_log_length;
+    let project = app.database.find_project_by_id(request.project_id)?;
+    let mut final_states = HashMap::new();
+    for change in &request.changes {
+        project.find_task_by_id(change.task_id)?;
+        final_states.insert(change.task_id, change.new_state);
+    }
+    if request.force != Some(true) {
+        for change in &request.changes {
+            if change.new_state != State::Done {
+                continue;
+            }
+            let task = project.find_task_by_id(change.task_id)?;
+            let mut unfinished = task
+                .dependencies
+                .iter()
+                .filter_map(|id| project.find_task_by_id(*id).ok())
+                .filter(|dependency| {
+                    let state = final_states
+                        .get(&dependency.id)
+                        .copied()
+                        .unwrap_or(dependency.state);
+                    !app.config.is_terminal(state)
+                })
+                .map(|dependency| dependency.id)
+                .collect::<Vec<_>>();
+            if !unfinished.is_empty() {
+                unfinished.sort_unstable();
+                return Err(ApiError::UnfinishedDependencies(unfinished).into());
+            }
+        }
+    }
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    let mut applied = Vec::new();
+    for change in request.changes {
+        let task = project.find_task_by_id_mut(change.task_id)?;
+        task.new_log_entry(
+            LogEntryType::StateChangedTo(change.new_state),
+            max_log_length,
+        );
+        let from = std::mem::replace(&mut task.state, change.new_state);
+        task.version += 1;
+        applied.push(AppliedStateChange {
+            task_id: task.id,
+            from,
+            to: change.new_state,
+            version: task.version,
+        });
+    }
+    if !applied.is_empty() {
+        app.record_mutation();
+    }
+    for change in &applied {
+        app.database.notify_state_change(request.project_id, change);
+    }
+    Ok(Response::new(Body::from(
+        json!({
+            "status": 200,
+            "description": "OK",
+            "changes": applied,
+        })
+        .to_string(),
+    )))
+}
+
+#[derive(Deserialize, Debug)]
+struct PostTaskPriorityChange {
+    project_id: usize,
+    task_id: usize,
+    new_priority: Priority,
+}
+
+async fn post_task_priority(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskPriorityChange>(request).await?;
+    let mut app = write_state(&app_state);
+    let max_log_length = app.config.max_log_length;
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    let task = project.find_task_by_id_mut(request.task_id)?;
+    task.new_log_entry(
+        LogEntryType::PriorityChangedTo(request.new_priority),
+        max_log_length,
+    );
+    let previous = std::mem::replace(&mut task.priority, request.new_priority);
+    task.version += 1;
+    let response = field_changed("priority", &previous, &task.priority, Some(task.version))?;
+    app.record_mutation();
+    Ok(response)
+}
+
+#[derive(Deserialize, Debug)]
+struct PostTaskDueRequest {
+    project_id: usize,
+    task_id: usize,
+    // Leaving this out or setting it to null clears the due date
+    #[serde(default, with = "chrono::serde::ts_seconds_option")]
+    due_date: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+async fn post_task_due(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskDueRequest>(request).await?;
+    let mut app = write_state(&app_state);
+    let max_log_length = app.config.max_log_length;
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    let task = project.find_task_by_id_mut(request.task_id)?;
+    task.new_log_entry(
+        LogEntryType::DueDateChanged(request.due_date),
+        max_log_length,
+    );
+    let previous = std::mem::replace(&mut task.due_date, request.due_date);
+    task.version += 1;
+    let response = field_changed(
+        "due_date",
+        &previous.map(|due_date| due_date.timestamp()),
+        &task.due_date.map(|due_date| due_date.timestamp()),
+        Some(task.version),
+    )?;
+    app.record_mutation();
+    Ok(response)
+}
+
+#[derive(Deserialize, Debug)]
+struct PostTaskDeleteRequest {
+    project_id: usize,
+    task_id: usize,
+}
+
+async fn post_task_delete(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskDeleteRequest>(request).await?;
+    let mut app = write_state(&app_state);
+    // A task is just as gone when its whole project has been deleted
+    let removed = match app.database.find_project_by_id_mut(request.project_id) {
+        Ok(project) => project.remove_task(request.task_id),
+        Err(_) => None,
+    };
+    let already_absent = removed.is_none();
+    let mut unlinked = Vec::new();
+    if let Some((task, dependents)) = removed {
+        app.database.record_history(
+            HistoryEventKind::TaskDeleted,
+            request.project_id,
+            Some(task.id),
+            &task.title,
+        );
+        app.record_mutation();
+        unlinked = dependents;
+    }
+    Ok(Response::new(Body::from(
+        json!({
+            "status": 200,
+            "description": "OK",
+            "already_absent": already_absent,
+            "dependency_removed_from": unlinked,
+        })
+        .to_string(),
+    )))
+}
+
+#[derive(Deserialize, Debug)]
+struct PostTaskMoveRequest {
+    source_project_id: usize,
+    task_id: usize,
+    target_project_id: usize,
+}
+
+// Moves a task to another project, where it gets a fresh ID. Its dependencies, relations and
+// parent all refer to tasks in the old project, so they are cleared.
+async fn post_task_move(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskMoveRequest>(request).await?;
+    if request.source_project_id == request.target_project_id {
+        return Err(
+            ApiError::InvalidValue("Task is already in the target project".to_owned()).into(),
+        );
+    }
+    let mut app = write_state(&app_state);
+    let max_log_length = app.config.max_log_length;
+    app.database.find_project_by_id(request.target_project_id)?;
+    let source = app
+        .database
+        .find_project_by_id_mut(request.source_project_id)?;
+    let (mut task, unlinked) = source
+        .remove_task(request.task_id)
+        .ok_or(ApiError::TaskNotFound(request.task_id))?;
+    let mut cleared_dependencies = task.dependencies.drain().collect::<Vec<_>>();
+    cleared_dependencies.sort_unstable();
+    task.relations.clear();
+    task.parent = None;
+    task.version += 1;
+    task.new_log_entry(
+        LogEntryType::Comment(format!(
+            "Moved from project {}, where it was task {}",
+            request.source_project_id, request.task_id
+        )),
+        max_log_length,
+    );
+    let title = task.title.clone();
+    let target = app
+        .database
+        .find_project_by_id_mut(request.target_project_id)?;
+    let task_id = target.insert_task(task);
+    app.database.record_history(
+        HistoryEventKind::TaskMovedOut,
+        request.source_project_id,
+        Some(request.task_id),
+        &title,
+    );
+    app.database.record_history(
+        HistoryEventKind::TaskMovedIn,
+        request.target_project_id,
+        Some(task_id),
+        &title,
+    );
+    app.record_mutation();
+    Ok(Response::new(Body::from(
+        json!({
+            "task_id": task_id,
+            "cleared_dependencies": cleared_dependencies,
+            "dependency_removed_from": unlinked,
+        })
+        .to_string(),
+    )))
+}
+
+#[derive(Deserialize, Debug)]
+struct PostTaskDuplicateRequest {
+    project_id: usize,
+    task_id: usize,
+    #[serde(default)]
+    copy_dependencies: bool,
+}
+
+// Starts a new task off as a copy of an existing one. Only what describes the work is copied, so
+// the copy starts out in Todo with an empty log.
+async fn post_task_duplicate(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskDuplicateRequest>(request).await?;
+    let mut app = write_state(&app_state);
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    let original = project.find_task_by_id(request.task_id)?;
+    let title = format!("{} (copy)", original.title);
+    let description = original.description.clone();
+    let priority = original.priority;
+    let tags = original.tags.clone();
+    let dependencies = if request.copy_dependencies {
+        original.dependencies.clone()
+    } else {
+        HashSet::new()
+    };
+    let new_task_id = project.create_task(title.clone(), description);
+    let task = project.find_task_by_id_mut(new_task_id)?;
+    task.priority = priority;
+    task.tags = tags;
+    task.dependencies = dependencies;
+    app.database.record_history(
+        HistoryEventKind::TaskCreated,
+        request.project_id,
+        Some(new_task_id),
+        &title,
+    );
+    app.record_mutation();
+    app.database
+        .notify_task_created(request.project_id, new_task_id, &title);
+    created(
+        task_location(request.project_id, new_task_id),
+        json!({ "task_id": new_task_id }),
+    )
+}
+
+#[derive(Deserialize, Debug)]
+struct PostTaskCommentRequest {
+    project_id: usize,
+    task_id: usize,
+    comment: String,
+}
+
+async fn post_task_comment(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskCommentRequest>(request).await?;
+    let mut app = write_state(&app_state);
+    let max_log_length = app.config.max_log_length;
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    let task = project.find_task_by_id_mut(request.task_id)?;
+    task.new_log_entry(LogEntryType::Comment(request.comment), max_log_length);
+    task.version += 1;
+    app.record_mutation();
+    Ok(Response::new(Body::from(
+        json!({"status": 200, "description": "OK"}).to_string(),
+    )))
+}
+
+#[derive(Deserialize, Debug)]
+struct PostTaskCommentPinRequest {
+    project_id: usize,
+    task_id: usize,
+    entry_id: usize,
+    pinned: bool,
+}
+
+async fn post_task_comment_pin(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskCommentPinRequest>(request).await?;
+    let mut app = write_state(&app_state);
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    let task = project.find_task_by_id_mut(request.task_id)?;
+    let entry = task.find_comment_by_id_mut(request.entry_id)?;
+    let previous = std::mem::replace(&mut entry.pinned, request.pinned);
+    task.version += 1;
+    let response = field_changed("pinned", &previous, &request.pinned, Some(task.version))?;
+    app.record_mutation();
+    Ok(response)
+}
+
+#[derive(Deserialize, Debug)]
+struct PostTaskCommentEditRequest {
+    project_id: usize,
+    task_id: usize,
+    entry_id: usize,
+    comment: String,
+}
+
+async fn post_task_comment_edit(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskCommentEditRequest>(request).await?;
+    let mut app = write_state(&app_state);
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    let task = project.find_task_by_id_mut(request.task_id)?;
+    let entry = task.find_comment_by_id_mut(request.entry_id)?;
+    let previous = std::mem::replace(
+        &mut entry.entry_type,
+        LogEntryType::Comment(request.comment.clone()),
+    );
+    let previous = match previous {
+        LogEntryType::Comment(comment) => comment,
+        _ => unreachable!("find_comment_by_id_mut only returns comments"),
+    };
+    entry.edited_at = Some(chrono::Utc::now());
+    task.version += 1;
+    let response = field_changed("comment", &previous, &request.comment, Some(task.version))?;
+    app.record_mutation();
+    Ok(response)
+}
+
+#[derive(Deserialize, Debug)]
+struct PostTaskCommentDeleteRequest {
+    project_id: usize,
+    task_id: usize,
+    entry_id: usize,
+}
+
+async fn post_task_comment_delete(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskCommentDeleteRequest>(request).await?;
+    let mut app = write_state(&app_state);
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    let task = project.find_task_by_id_mut(request.task_id)?;
+    task.find_comment_by_id_mut(request.entry_id)?;
+    let entry_index = task.log_entry_position(request.entry_id)?;
+    task.log.remove(entry_index);
+    task.version += 1;
+    let version = task.version;
+    app.record_mutation();
+    Ok(Response::new(Body::from(
+        json!({"status": 200, "description": "OK", "version": version}).to_string(),
+    )))
+}
+
+#[derive(Deserialize, Debug)]
+struct TaskPinnedCommentsRequest {
+    project_id: usize,
+    task_id: usize,
+    #[serde(default)]
+    tz: Option<String>,
+}
+
+async fn task_pinned_comments(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<TaskPinnedCommentsRequest>(request).await?;
+    let time_zone = parse_time_zone(request.tz.as_deref())?;
+    let app = read_state(&app_state);
+    let project = app.database.find_project_by_id(request.project_id)?;
+    let task = project.find_task_by_id(request.task_id)?;
+    let comments = task
+        .log
+        .iter()
+        .filter(|entry| entry.pinned)
+        .collect::<Vec<_>>();
+    let mut response = json!({ "comments": comments });
+    if let Some(time_zone) = time_zone {
+        localize_timestamps(&mut response, time_zone);
+    }
+    Ok(Response::new(Body::from(response.to_string())))
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+enum UniqueBy {
+    Title,
+}
+
+#[derive(Deserialize, Debug)]
+struct PostTaskCreateRequest {
+    project_id: usize,
+    title: String,
+    description: String,
+    #[serde(default)]
+    unique_by: Option<UniqueBy>,
+}
+
+async fn post_task_create(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskCreateRequest>(request).await?;
+    let title = non_empty_name(&request.title, "Task title")?;
+    let mut app = write_state(&app_state);
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    let existing = match request.unique_by {
+        Some(UniqueBy::Title) => project
+            .tasks
+            .iter()
+            .find(|task| task.title == title)
+            .map(|task| task.id),
+        None => None,
+    };
+    if let Some(task_id) = existing {
+        return Ok(Response::new(Body::from(
+            json!({ "task_id": task_id, "created": false }).to_string(),
+        )));
+    }
+    let task_id = project.create_task(title.clone(), request.description);
+    app.database.record_history(
+        HistoryEventKind::TaskCreated,
+        request.project_id,
+        Some(task_id),
+        &title,
+    );
+    app.record_mutation();
+    app.database
+        .notify_task_created(request.project_id, task_id, &title);
+    created(
+        task_location(request.project_id, task_id),
+        json!({ "task_id": task_id, "created": true }),
+    )
+}
+
+#[derive(Deserialize, Debug)]
+struct PostTaskChecklistAddRequest {
+    project_id: usize,
+    task_id: usize,
+    text: String,
+}
+
+async fn post_task_checklist_add(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskChecklistAddRequest>(request).await?;
+    let text = request.text.trim();
+    if text.is_empty() {
+        return Err(
+            ApiError::InvalidValue("Checklist item text cannot be empty".to_owned()).into(),
+        );
+    }
+    let mut app = write_state(&app_state);
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    let task = project.find_task_by_id_mut(request.task_id)?;
+    task.checklist.push(ChecklistItem {
+        text: text.to_owned(),
+        done: false,
+    });
+    task.version += 1;
+    let item_index = task.checklist.len() - 1;
+    app.record_mutation();
+    Ok(Response::new(Body::from(
+        json!({"status": 200, "description": "OK", "item_index": item_index}).to_string(),
+    )))
+}
+
+#[derive(Deserialize, Debug)]
+struct PostTaskChecklistPromoteRequest {
+    project_id: usize,
+    task_id: usize,
+    item_index: usize,
+}
+
+// Turns a checklist item into a task of its own, which the original task then depends on
+async fn post_task_checklist_promote(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskChecklistPromoteRequest>(request).await?;
+    let mut app = write_state(&app_state);
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    let task = project.find_task_by_id_mut(request.task_id)?;
+    if request.item_index >= task.checklist.len() {
+        return Err(ApiError::InvalidValue(format!(
+            "Task {} has no checklist item {}",
+            request.task_id, request.item_index
+        ))
+        .into());
+    }
+    let item = task.checklist.remove(request.item_index);
+    let new_task_id = project.create_task(item.text.clone(), String::new());
+    let task = project.find_task_by_id_mut(request.task_id)?;
+    task.dependencies.insert(new_task_id);
+    task.version += 1;
+    app.database.record_history(
+        HistoryEventKind::TaskCreated,
+        request.project_id,
+        Some(new_task_id),
+        &item.text,
+    );
+    app.record_mutation();
+    app.database
+        .notify_task_created(request.project_id, new_task_id, &item.text);
+    created(
+        task_location(request.project_id, new_task_id),
+        json!({ "task_id": new_task_id }),
+    )
+}
+
+#[derive(Deserialize, Debug)]
+struct PostTaskReparentBulkRequest {
+    project_id: usize,
+    task_ids: Vec<usize>,
+    // None moves the tasks to the top level
+    #[serde(default)]
+    new_parent: Option<usize>,
+}
+
+// Outcome for one task of a request acting on several, which carries on past failures
+#[derive(Serialize, Debug)]
+struct BatchResult {
+    task_id: usize,
+    error: Option<String>,
+}
+
+async fn post_task_reparent_bulk(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskReparentBulkRequest>(request).await?;
+    let mut app = write_state(&app_state);
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    if let Some(new_parent) = request.new_parent {
+        project.find_task_by_id(new_parent)?;
+    }
+    let mut results = Vec::new();
+    let mut updated = 0;
+    for task_id in request.task_ids {
+        let error = match request.new_parent {
+            _ if project.find_task_by_id(task_id).is_err() => {
+                Some(format!("Could not find task with ID: {}", task_id))
+            }
+            Some(new_parent) if project.is_under(new_parent, task_id) => Some(format!(
+                "Task {} can't be moved under itself or a task below it",
+                task_id
+            )),
+            _ => None,
+        };
+        if error.is_none() {
+            let task = project.find_task_by_id_mut(task_id)?;
+            if task.parent != request.new_parent {
+                task.parent = request.new_parent;
+                task.version += 1;
+                updated += 1;
+            }
+        }
+        results.push(BatchResult { task_id, error });
+    }
+    if updated > 0 {
+        app.record_mutation();
+    }
+    Ok(Response::new(Body::from(
+        json!({"status": 200, "description": "OK", "updated": updated, "results": results})
+            .to_string(),
+    )))
+}
+
+#[derive(Deserialize, Debug)]
+struct PostTaskTitleRequest {
+    project_id: usize,
+    task_id: usize,
+    title: String,
+}
+
+async fn post_task_title(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskTitleRequest>(request).await?;
+    let title = non_empty_name(&request.title, "Task title")?;
+    let mut app = write_state(&app_state);
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    let task = project.find_task_by_id_mut(request.task_id)?;
+    let previous = std::mem::replace(&mut task.title, title);
+    task.version += 1;
+    let response = field_changed("title", &previous, &task.title, Some(task.version))?;
+    app.record_mutation();
+    Ok(response)
+}
+
+#[derive(Deserialize, Debug)]
+struct PostTaskDescriptionRequest {
+    project_id: usize,
+    task_id: usize,
+    description: String,
+}
+
+async fn post_task_description(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskDescriptionRequest>(request).await?;
+    let mut app = write_state(&app_state);
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    let task = project.find_task_by_id_mut(request.task_id)?;
+    let previous = std::mem::replace(&mut task.description, request.description);
+    task.version += 1;
+    let response = field_changed(
+        "description",
+        &previous,
+        &task.description,
+        Some(task.version),
+    )?;
+    app.record_mutation();
+    Ok(response)
+}
+
+// Whether to add a value to or remove it from one of a task's sets, like its dependencies
+#[derive(Deserialize, Debug)]
+enum SetAction {
+    Add,
+    Remove,
+}
+
+#[derive(Deserialize, Debug)]
+struct PostTaskDependencyRequest {
+    project_id: usize,
+    task_id: usize,
+    dependency: usize,
+    action: SetAction,
+}
+
+async fn post_task_dependency(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskDependencyRequest>(request).await?;
+    let mut app = write_state(&app_state);
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    // Removing is left unchecked, so that dangling dependencies can still be cleaned up
+    if let SetAction::Add = request.action {
+        if request.dependency == request.task_id {
+            return Err(ApiError::DependencyCycle(
+                "A task can't depend on itself",
+                vec![request.task_id, request.task_id],
+            )
+            .into());
+        }
+        project.find_task_by_id(request.dependency)?;
+        // The new dependency closes a cycle if it already leads back to the task
+        if let Some(path) = project.dependency_path(request.dependency, request.task_id) {
+            let cycle = std::iter::once(request.task_id).chain(path).collect();
+            return Err(ApiError::DependencyCycle(
+                "Adding this dependency would create a cycle",
+                cycle,
+            )
+            .into());
+        }
+    }
+    let task = project.find_task_by_id_mut(request.task_id)?;
+    let sorted_dependencies = |task: &Task| {
+        let mut dependencies = task.dependencies.iter().copied().collect::<Vec<_>>();
+        dependencies.sort_unstable();
+        dependencies
+    };
+    let previous = sorted_dependencies(task);
+    match request.action {
+        SetAction::Add => task.dependencies.insert(request.dependency),
+        SetAction::Remove => task.dependencies.remove(&request.dependency),
+    };
+    task.version += 1;
+    let response = field_changed(
+        "dependencies",
+        &previous,
+        &sorted_dependencies(task),
+        Some(task.version),
+    )?;
+    app.record_mutation();
+    Ok(response)
+}
+
+#[derive(Deserialize, Debug)]
+struct PostTaskTagRequest {
+    project_id: usize,
+    task_id: usize,
+    tag: String,
+    action: SetAction,
+}
+
+async fn post_task_tag(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskTagRequest>(request).await?;
+    let tag = normalize_tag(&request.tag);
+    if tag.is_empty() {
+        return Err(ApiError::InvalidValue("Tag cannot be empty".to_owned()).into());
+    }
+    let mut app = write_state(&app_state);
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    let task = project.find_task_by_id_mut(request.task_id)?;
+    let sorted_tags = |task: &Task| {
+        let mut tags = task.tags.iter().cloned().collect::<Vec<_>>();
+        tags.sort_unstable();
+        tags
+    };
+    let previous = sorted_tags(task);
+    match request.action {
+        SetAction::Add => {
+            task.tags.insert(tag);
+        }
+        // Also catches tags which were stored before they were normalized, e.g. by an import
+        SetAction::Remove => task.tags.retain(|task_tag| normalize_tag(task_tag) != tag),
+    }
+    task.version += 1;
+    let response = field_changed("tags", &previous, &sorted_tags(task), Some(task.version))?;
+    app.record_mutation();
+    Ok(response)
+}
+
+#[derive(Deserialize, Debug)]
+struct PostTaskChainRequest {
+    project_id: usize,
+    task_ids: Vec<usize>,
+}
+
+#[derive(Serialize, Debug)]
+struct DependencyEdge {
+    task_id: usize,
+    dependency: usize,
+}
+
+// Makes each task in the list depend on the one before it. Either every edge is added, or none
+// are.
+async fn post_task_chain(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskChainRequest>(request).await?;
+    let mut seen = HashSet::new();
+    if let Some(repeated) = request.task_ids.iter().find(|id| !seen.insert(**id)) {
+        return Err(ApiError::InvalidValue(format!(
+            "Task {} appears more than once in the chain",
+            repeated
+        ))
+        .into());
+    }
+    let mut app = write_state(&app_state);
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    for task_id in &request.task_ids {
+        project.find_task_by_id(*task_id)?;
+    }
+    let mut edges = Vec::<DependencyEdge>::new();
+    for pair in request.task_ids.windows(2) {
+        if project
+            .find_task_by_id(pair[1])?
+            .dependencies
+            .contains(&pair[0])
+        {
+            continue;
+        }
+        // Like POST /task/dependency, each new edge closes a cycle if the dependency already
+        // leads back to the task, counting the edges added before it
+        if let Some(path) = project.dependency_path(pair[0], pair[1]) {
+            for edge in &edges {
+                project
+                    .find_task_by_id_mut(edge.task_id)?
+                    .dependencies
+                    .remove(&edge.dependency);
+            }
+            let cycle = std::iter::once(pair[1]).chain(path).collect();
+            return Err(
+                ApiError::DependencyCycle("Chain would create a dependency cycle", cycle).into(),
+            );
+        }
+        project
+            .find_task_by_id_mut(pair[1])?
+            .dependencies
+            .insert(pair[0]);
+        edges.push(DependencyEdge {
+            task_id: pair[1],
+            dependency: pair[0],
+        });
+    }
+    for edge in &edges {
+        project.find_task_by_id_mut(edge.task_id)?.version += 1;
+    }
+    if !edges.is_empty() {
+        app.record_mutation();
+    }
+    Ok(Response::new(Body::from(
+        json!({ "edges": edges }).to_string(),
+    )))
+}
+
+#[derive(Deserialize, Debug)]
+struct PostTaskRelationRequest {
+    project_id: usize,
+    task_id: usize,
+    kind: RelationKind,
+    target: usize,
+}
+
+async fn post_task_relation_add(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskRelationRequest>(request).await?;
+    if request.task_id == request.target {
+        return Err(ApiError::InvalidValue("A task cannot be related to itself".to_owned()).into());
+    }
+    let mut app = write_state(&app_state);
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    project.find_task_by_id(request.target)?;
+    let task = project.find_task_by_id_mut(request.task_id)?;
+    let relation = Relation {
+        kind: request.kind,
+        task_id: request.target,
+    };
+    // Adding a relation which is already there changes nothing
+    if !task.relations.contains(&relation) {
+        task.relations.push(relation);
+        task.version += 1;
+        app.record_mutation();
+    }
+    Ok(Response::new(Body::from(
+        json!({"status": 200, "description": "OK"}).to_string(),
+    )))
+}
+
+async fn post_task_relation_remove(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskRelationRequest>(request).await?;
+    let mut app = write_state(&app_state);
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    let task = project.find_task_by_id_mut(request.task_id)?;
+    let count = task.relations.len();
+    task.relations
+        .retain(|relation| relation.kind != request.kind || relation.task_id != request.target);
+    if task.relations.len() < count {
+        task.version += 1;
+        app.record_mutation();
+    }
+    Ok(Response::new(Body::from(
+        json!({"status": 200, "description": "OK"}).to_string(),
+    )))
+}
+
+#[derive(Deserialize, Debug)]
+struct PostTaskAttachmentAddRequest {
+    project_id: usize,
+    task_id: usize,
+    filename: String,
+    url: String,
+    #[serde(default)]
+    size_bytes: Option<u64>,
+    #[serde(default)]
+    content_type: Option<String>,
+}
+
+fn check_attachment_url(url: &str) -> Result<(), ApiError> {
+    let valid = url.parse::<hyper::Uri>().is_ok_and(|uri| {
+        matches!(uri.scheme_str(), Some("http" | "https")) && uri.host().is_some()
+    });
+    if !valid {
+        return Err(ApiError::InvalidValue(format!(
+            "Invalid attachment URL `{}`, expected an http:// or https:// URL",
+            url
+        )));
+    }
+    Ok(())
+}
+
+// Filenames identify attachments within a task, so they have to be unique
+async fn post_task_attachment_add(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskAttachmentAddRequest>(request).await?;
+    let filename = request.filename.trim();
+    if filename.is_empty() {
+        return Err(
+            ApiError::InvalidValue("Attachment filename cannot be empty".to_owned()).into(),
+        );
+    }
+    let url = request.url.trim();
+    check_attachment_url(url)?;
+    let mut app = write_state(&app_state);
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    let task = project.find_task_by_id_mut(request.task_id)?;
+    if task
+        .attachments
+        .iter()
+        .any(|attachment| attachment.filename == filename)
+    {
+        return Err(ApiError::InvalidValue(format!(
+            "Task already has an attachment named `{}`",
+            filename
+        ))
+        .into());
+    }
+    task.attachments.push(Attachment {
+        filename: filename.to_owned(),
+        url: url.to_owned(),
+        size_bytes: request.size_bytes,
+        content_type: request.content_type,
+    });
+    task.version += 1;
+    let version = task.version;
+    app.record_mutation();
+    Ok(Response::new(Body::from(
+        json!({"status": 200, "description": "OK", "version": version}).to_string(),
+    )))
+}
+
+#[derive(Deserialize, Debug)]
+struct PostTaskAttachmentRemoveRequest {
+    project_id: usize,
+    task_id: usize,
+    filename: String,
+}
+
+async fn post_task_attachment_remove(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskAttachmentRemoveRequest>(request).await?;
+    let filename = request.filename.trim();
+    let mut app = write_state(&app_state);
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    let task = project.find_task_by_id_mut(request.task_id)?;
+    let count = task.attachments.len();
+    task.attachments
+        .retain(|attachment| attachment.filename != filename);
+    let removed = task.attachments.len() < count;
+    if removed {
+        task.version += 1;
+        app.record_mutation();
+    }
+    Ok(Response::new(Body::from(
+        json!({"status": 200, "description": "OK", "already_absent": !removed}).to_string(),
+    )))
+}
+
+#[derive(Deserialize, Debug)]
+struct PostTaskAssigneeRequest {
+    project_id: usize,
+    task_id: usize,
+    assignee: Option<String>,
+}
+
+async fn post_task_assignee(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskAssigneeRequest>(request).await?;
+    let mut app = write_state(&app_state);
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    let task = project.find_task_by_id_mut(request.task_id)?;
+    let previous = std::mem::replace(&mut task.assignee, request.assignee);
+    task.version += 1;
+    let response = field_changed("assignee", &previous, &task.assignee, Some(task.version))?;
+    app.record_mutation();
+    Ok(response)
+}
+
+#[derive(Deserialize, Debug)]
+struct PostTaskEstimateRequest {
+    project_id: usize,
+    task_id: usize,
+    estimate_minutes: Option<u64>,
+}
+
+async fn post_task_estimate(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskEstimateRequest>(request).await?;
+    let mut app = write_state(&app_state);
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    let task = project.find_task_by_id_mut(request.task_id)?;
+    let previous = std::mem::replace(&mut task.estimate_minutes, request.estimate_minutes);
+    task.version += 1;
+    let response = field_changed(
+        "estimate_minutes",
+        &previous,
+        &task.estimate_minutes,
+        Some(task.version),
+    )?;
+    app.record_mutation();
+    Ok(response)
+}
+
+#[derive(Deserialize, Debug)]
+struct PostTaskActualRequest {
+    project_id: usize,
+    task_id: usize,
+    actual_minutes: Option<u64>,
+}
+
+async fn post_task_actual(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskActualRequest>(request).await?;
+    let mut app = write_state(&app_state);
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    let task = project.find_task_by_id_mut(request.task_id)?;
+    let previous = std::mem::replace(&mut task.actual_minutes, request.actual_minutes);
+    task.version += 1;
+    let response = field_changed(
+        "actual_minutes",
+        &previous,
+        &task.actual_minutes,
+        Some(task.version),
+    )?;
+    app.record_mutation();
+    Ok(response)
+}
+
+#[derive(Deserialize, Debug)]
+struct PostTaskArchiveRequest {
+    project_id: usize,
+    task_id: usize,
+    archived: bool,
+}
+
+async fn post_task_archive(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskArchiveRequest>(request).await?;
+    let mut app = write_state(&app_state);
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    let task = project.find_task_by_id_mut(request.task_id)?;
+    let previous = std::mem::replace(&mut task.archived, request.archived);
+    task.version += 1;
+    let response = field_changed("archived", &previous, &task.archived, Some(task.version))?;
+    if request.archived && !previous {
+        let title = task.title.clone();
+        app.database.record_history(
+            HistoryEventKind::TaskArchived,
+            request.project_id,
+            Some(request.task_id),
+            &title,
+        );
+    }
+    app.record_mutation();
+    Ok(response)
+}
+
+#[derive(Deserialize, Debug)]
+struct PostTaskColorRequest {
+    project_id: usize,
+    task_id: usize,
+    color: Option<String>,
+}
+
+// Accepts "#rgb" and "#rrggbb", in either case, and gives back the lowercase form
+fn normalize_color(color: &str) -> Result<String, ApiError> {
+    let digits = color.strip_prefix('#').unwrap_or("");
+    if !matches!(digits.len(), 3 | 6) || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(ApiError::InvalidValue(format!(
+            "Invalid color `{}`, expected a hex color like #ff0000",
+            color
+        )));
+    }
+    Ok(color.to_ascii_lowercase())
+}
+
+async fn post_task_color(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostTaskColorRequest>(request).await?;
+    let color = request.color.as_deref().map(normalize_color).transpose()?;
+    let mut app = write_state(&app_state);
+    let project = app.database.find_project_by_id_mut(request.project_id)?;
+    let task = project.find_task_by_id_mut(request.task_id)?;
+    let previous = std::mem::replace(&mut task.color, color);
+    task.version += 1;
+    let response = field_changed("color", &previous, &task.color, Some(task.version))?;
+    app.record_mutation();
+    Ok(response)
+}
+
+#[derive(Default, Deserialize, Debug)]
+struct ReportEstimationAccuracyRequest {
+    #[serde(default)]
+    project_id: Option<usize>,
+}
+
+async fn report_estimation_accuracy(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_optional_body::<ReportEstimationAccuracyRequest>(request).await?;
+    let app = read_state(&app_state);
+    let accuracy = match request.project_id {
+        Some(project_id) => {
+            let project = app.database.find_project_by_id(project_id)?;
+            metrics::estimation_accuracy(project.tasks.iter())
+        }
+        None => metrics::estimation_accuracy(
+            app.database
+                .projects
+                .values()
+                .flat_map(|project| project.tasks.iter()),
+        ),
+    };
+    Ok(Response::new(Body::from(serde_json::to_string(&accuracy)?)))
+}
+
+#[derive(Deserialize, Debug)]
+struct ReportCompletionsByDayRequest {
+    #[serde(with = "chrono::serde::ts_seconds")]
+    since: chrono::DateTime<chrono::Utc>,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    until: chrono::DateTime<chrono::Utc>,
+    #[serde(default)]
+    project_id: Option<usize>,
+    // Time zone whose calendar days the completions are counted by, UTC if not given
+    #[serde(default)]
+    tz: Option<String>,
+}
+
+async fn report_completions_by_day(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<ReportCompletionsByDayRequest>(request).await?;
+    let time_zone = parse_time_zone(request.tz.as_deref())?.unwrap_or(chrono_tz::UTC);
+    let app = read_state(&app_state);
+    let days = match request.project_id {
+        Some(project_id) => {
+            let project = app.database.find_project_by_id(project_id)?;
+            metrics::completions_by_day(
+                project.tasks.iter(),
+                request.since,
+                request.until,
+                time_zone,
+            )
+        }
+        None => metrics::completions_by_day(
+            app.database
+                .projects
+                .values()
+                .flat_map(|project| project.tasks.iter()),
+            request.since,
+            request.until,
+            time_zone,
+        ),
+    };
+    Ok(Response::new(Body::from(serde_json::to_string(&days)?)))
+}
+
+#[derive(Default, Deserialize, Debug)]
+struct ReportLoadRequest {
+    #[serde(default)]
+    assignee: Option<String>,
+}
+
+#[derive(Default, Serialize, Debug)]
+struct AssigneeLoad {
+    remaining_minutes: u64,
+    task_count: usize,
+}
+
+async fn report_load(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_optional_body::<ReportLoadRequest>(request).await?;
+    let app = read_state(&app_state);
+    let mut assignees = BTreeMap::<String, AssigneeLoad>::new();
+    let mut unassigned = AssigneeLoad::default();
+    let tasks = app
+        .database
+        .projects
+        .values()
+        .flat_map(|project| project.tasks.iter())
+        .filter(|task| !app.config.is_terminal(task.state));
+    for task in tasks {
+        let load = match &task.assignee {
+            Some(assignee) => {
+                if matches!(&request.assignee, Some(wanted) if wanted != assignee) {
+                    continue;
+                }
+                assignees.entry(assignee.clone()).or_default()
+            }
+            None if request.assignee.is_none() => &mut unassigned,
+            None => continue,
+        };
+        // Estimates aren't bounded, so totals stop at the largest value rather than overflowing
+        load.remaining_minutes = load
+            .remaining_minutes
+            .saturating_add(task.estimate_minutes.unwrap_or(0));
+        load.task_count += 1;
+    }
+    let response = if request.assignee.is_some() {
+        json!({ "assignees": assignees })
+    } else {
+        json!({ "assignees": assignees, "unassigned": unassigned })
+    };
+    Ok(Response::new(Body::from(response.to_string())))
+}
+
+#[derive(Deserialize, Debug)]
+struct ProjectRebalanceRequest {
+    project_id: usize,
+}
+
+#[derive(Serialize, Debug)]
+struct RebalanceMove {
+    task_id: usize,
+    from: String,
+    to: String,
+    estimate_minutes: u64,
+}
+
+// Suggests reassignments which even out the estimated open effort in a project, without applying
+// them. Greedily moves the largest task off the most loaded assignee onto the least loaded one, as
+// long as that narrows the gap between them. Only tasks which haven't been started are moved, and
+// each at most once.
+async fn project_rebalance(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<ProjectRebalanceRequest>(request).await?;
+    let app = read_state(&app_state);
+    let project = app.database.find_project_by_id(request.project_id)?;
+    let mut loads = BTreeMap::<String, u64>::new();
+    let mut movable = Vec::new();
+    let open_tasks = project
+        .tasks
+        .iter()
+        .filter(|task| !task.archived && !app.config.is_terminal(task.state));
+    for task in open_tasks {
+        if let Some(assignee) = &task.assignee {
+            let estimate = task.estimate_minutes.unwrap_or(0);
+            *loads.entry(assignee.clone()).or_default() += estimate;
+            if task.state != State::InProgress && estimate > 0 {
+                movable.push((task.id, assignee.clone(), estimate));
+            }
+        }
+    }
+    let loads_before = loads.clone();
+    let mut moves = Vec::new();
+    loop {
+        let (most, least) = match (
+            loads.iter().max_by_key(|(_, load)| **load),
+            loads.iter().min_by_key(|(_, load)| **load),
+        ) {
+            (Some((most, max)), Some((least, min))) if max > min => (most.clone(), least.clone()),
+            _ => break,
+        };
+        let gap = loads[&most] - loads[&least];
+        let best = movable
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, assignee, estimate))| *assignee == most && *estimate < gap)
+            .max_by_key(|(_, (task_id, _, estimate))| (*estimate, std::cmp::Reverse(*task_id)))
+            .map(|(index, _)| index);
+        let (task_id, from, estimate_minutes) = match best {
+            Some(index) => movable.remove(index),
+            None => break,
+        };
+        *loads.get_mut(&from).unwrap() -= estimate_minutes;
+        *loads.get_mut(&least).unwrap() += estimate_minutes;
+        moves.push(RebalanceMove {
+            task_id,
+            from,
+            to: least,
+            estimate_minutes,
+        });
+    }
+    let response = json!({
+        "moves": moves,
+        "loads_before": loads_before,
+        "loads_after": loads,
+    });
+    Ok(Response::new(Body::from(response.to_string())))
+}
+
+#[derive(Deserialize, Debug)]
+struct TaskSubtreeEffortRequest {
+    project_id: usize,
+    task_id: usize,
+}
+
+// Sums the estimates of a task and everything it transitively depends on
+async fn task_subtree_effort(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<TaskSubtreeEffortRequest>(request).await?;
+    let app = read_state(&app_state);
+    let project = app.database.find_project_by_id(request.project_id)?;
+    project.find_task_by_id(request.task_id)?;
+    if let Some(cycle) =
+        project.find_cycle_from(request.task_id, &mut Vec::new(), &mut HashSet::new())
+    {
+        return Err(ApiError::DependencyCycle("Dependencies form a cycle", cycle).into());
+    }
+    let mut total_minutes: u64 = 0;
+    let mut remaining_minutes: u64 = 0;
+    let mut skipped_tasks = 0;
+    let closure = project.dependency_closure(request.task_id);
+    for task_id in &closure {
+        let task = project.find_task_by_id(*task_id)?;
+        match task.estimate_minutes {
+            // Like report_load, saturating instead of overflowing on huge estimates
+            Some(estimate) => {
+                total_minutes = total_minutes.saturating_add(estimate);
+                if !app.config.is_terminal(task.state) {
+                    remaining_minutes = remaining_minutes.saturating_add(estimate);
+                }
+            }
+            None => skipped_tasks += 1,
+        }
+    }
+    let response = json!({
+        "task_count": closure.len(),
+        "total_minutes": total_minutes,
+        "remaining_minutes": remaining_minutes,
+        "skipped_tasks": skipped_tasks,
+    });
+    Ok(Response::new(Body::from(response.to_string())))
+}
+
+#[derive(Deserialize, Debug)]
+struct TaskBlocksRequest {
+    project_id: usize,
+    task_id: usize,
+}
+
+// Lists the open tasks which wait on this task and nothing else. A dependency stops holding a task
+// up once it reaches a terminal state, and dependencies which no longer exist don't hold it up.
+async fn task_blocks(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<TaskBlocksRequest>(request).await?;
+    let app = read_state(&app_state);
+    let project = app.database.find_project_by_id(request.project_id)?;
+    let task = project.find_task_by_id(request.task_id)?;
+    let is_pending = |task_id: usize| {
+        project
+            .find_task_by_id(task_id)
+            .is_ok_and(|dependency| !app.config.is_terminal(dependency.state))
+    };
+    let blocked_tasks = if app.config.is_terminal(task.state) {
+        Vec::new()
+    } else {
+        project
+            .dependents_of(task.id)
+            .filter(|dependent| !app.config.is_terminal(dependent.state))
+            .filter(|dependent| {
+                dependent
+                    .dependencies
+                    .iter()
+                    .all(|dependency| *dependency == task.id || !is_pending(*dependency))
+            })
+            .map(TaskPeek::new)
+            .collect()
+    };
+    Ok(Response::new(Body::from(
+        json!({ "blocked_tasks": blocked_tasks }).to_string(),
+    )))
+}
+
+#[derive(Deserialize, Debug)]
+struct ProjectBottlenecksRequest {
+    project_id: usize,
+}
+
+#[derive(Serialize, Debug)]
+struct Bottleneck {
+    id: usize,
+    title: String,
+    state: State,
+    dependent_count: usize,
+    in_cycle: bool,
+}
+
+async fn project_bottlenecks(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<ProjectBottlenecksRequest>(request).await?;
+    let app = read_state(&app_state);
+    let project = app.database.find_project_by_id(request.project_id)?;
+    let dependents = project.dependents_map();
+    let mut bottlenecks = project
+        .tasks
+        .iter()
+        .filter(|task| !app.config.is_terminal(task.state))
+        .map(|task| {
+            let (transitive, in_cycle) = Project::transitive_dependents(task.id, &dependents);
+            Bottleneck {
+                id: task.id,
+                title: task.title.clone(),
+                state: task.state,
+                dependent_count: transitive.len(),
+                in_cycle,
+            }
+        })
+        .collect::<Vec<_>>();
+    bottlenecks.sort_by_key(|bottleneck| std::cmp::Reverse(bottleneck.dependent_count));
+    let cycles = bottlenecks
+        .iter()
+        .filter(|bottleneck| bottleneck.in_cycle)
+        .map(|bottleneck| bottleneck.id)
+        .collect::<Vec<_>>();
+    Ok(Response::new(Body::from(
+        json!({ "tasks": bottlenecks, "cycle_task_ids": cycles }).to_string(),
+    )))
+}
+
+#[derive(Deserialize, Debug)]
+struct ProjectScheduleRequest {
+    project_id: usize,
+}
+
+// Orders a project's tasks so that every task comes after everything it depends on. Works in
+// rounds, each taking every task whose dependencies all came in earlier rounds, ordered by ID. So
+// tasks without dependencies all come first.
+async fn project_schedule(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<ProjectScheduleRequest>(request).await?;
+    let app = read_state(&app_state);
+    let project = app.database.find_project_by_id(request.project_id)?;
+    let dependents = project.dependents_map();
+    // Number of dependencies each task is still waiting on, leaving out dangling ones
+    let mut waiting_on = project
+        .tasks
+        .iter()
+        .map(|task| {
+            let count = task
+                .dependencies
+                .iter()
+                .filter(|id| project.find_task_by_id(**id).is_ok())
+                .count();
+            (task.id, count)
+        })
+        .collect::<HashMap<_, _>>();
+    let mut ready = waiting_on
+        .iter()
+        .filter(|(_, count)| **count == 0)
+        .map(|(id, _)| *id)
+        .collect::<Vec<_>>();
+    let mut order = Vec::new();
+    while !ready.is_empty() {
+        ready.sort_unstable();
+        let mut next = Vec::new();
+        for task_id in &ready {
+            for dependent in dependents.get(task_id).into_iter().flatten() {
+                let count = waiting_on.get_mut(dependent).unwrap();
+                *count -= 1;
+                if *count == 0 {
+                    next.push(*dependent);
+                }
+            }
+        }
+        order.append(&mut ready);
+        ready = next;
+    }
+    if order.len() < project.tasks.len() {
+        let cycle = project.find_dependency_cycle().unwrap_or_default();
+        return Err(ApiError::DependencyCycle("Dependencies form a cycle", cycle).into());
+    }
+    Ok(Response::new(Body::from(
+        json!({ "task_ids": order }).to_string(),
+    )))
+}
+
+#[derive(Deserialize, Debug)]
+struct ProjectDagInfoRequest {
+    project_id: usize,
+}
+
+#[derive(Serialize, Debug)]
+struct DagComponent {
+    task_ids: Vec<usize>,
+    // Tasks which nothing else in the project depends on
+    roots: Vec<usize>,
+    // Tasks which don't depend on anything
+    leaves: Vec<usize>,
+}
+
+// Summarises the shape of a project's dependency graph. Dangling dependencies are ignored.
+async fn project_dag_info(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<ProjectDagInfoRequest>(request).await?;
+    let app = read_state(&app_state);
+    let project = app.database.find_project_by_id(request.project_id)?;
+    let dependents = project.dependents_map();
+    let cycle = project.find_dependency_cycle();
+    let cycle_task_ids = project
+        .tasks
+        .iter()
+        .filter(|task| Project::transitive_dependents(task.id, &dependents).1)
+        .map(|task| task.id)
+        .collect::<Vec<_>>();
+    let index_of = |id: usize| project.task_position(id).ok();
+    let mut parents = (0..project.tasks.len()).collect::<Vec<_>>();
+    for (index, task) in project.tasks.iter().enumerate() {
+        for dependency in task.dependencies.iter().filter_map(|id| index_of(*id)) {
+            let (a, b) = (root(&mut parents, index), root(&mut parents, dependency));
+            parents[a] = b;
+        }
+    }
+    let mut components = BTreeMap::<usize, DagComponent>::new();
+    for (index, task) in project.tasks.iter().enumerate() {
+        let component = components
+            .entry(root(&mut parents, index))
+            .or_insert_with(|| DagComponent {
+                task_ids: Vec::new(),
+                roots: Vec::new(),
+                leaves: Vec::new(),
+            });
+        component.task_ids.push(task.id);
+        if !dependents.contains_key(&task.id) {
+            component.roots.push(task.id);
+        }
+        if !task.dependencies.iter().any(|id| index_of(*id).is_some()) {
+            component.leaves.push(task.id);
+        }
+    }
+    // Ordered by each component's lowest task ID
+    let mut components = components.into_values().collect::<Vec<_>>();
+    components.sort_by_key(|component| component.task_ids[0]);
+    Ok(Response::new(Body::from(
+        json!({
+            "acyclic": cycle.is_none(),
+            "cycle": cycle,
+            "cycle_task_ids": cycle_task_ids,
+            "component_count": components.len(),
+            "components": components,
+        })
+        .to_string(),
+    )))
+}
+
+// Taken by both GET /task/dependents and GET /task/global_dependents
+#[derive(Deserialize, Debug)]
+struct TaskDependentsRequest {
+    project_id: usize,
+    task_id: usize,
+}
+
+#[derive(Serialize, Debug)]
+struct TaskRef {
+    id: usize,
+    title: String,
+}
+
+// Lists the tasks in the project which directly depend on the given task
+async fn task_dependents(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<TaskDependentsRequest>(request).await?;
+    let app = read_state(&app_state);
+    let project = app.database.find_project_by_id(request.project_id)?;
+    project.find_task_by_id(request.task_id)?;
+    let dependents = project
+        .dependents_of(request.task_id)
+        .map(|task| TaskRef {
+            id: task.id,
+            title: task.title.clone(),
+        })
+        .collect::<Vec<_>>();
+    Ok(Response::new(Body::from(
+        json!({ "dependents": dependents }).to_string(),
+    )))
+}
+
+#[derive(Serialize, Debug)]
+struct GlobalTaskRef {
+    project_id: usize,
+    task_id: usize,
+    title: String,
+}
+
+// Dependencies can only refer to tasks within the same project for now, so this only scans the
+// given project. Results carry their project ID so that cross-project references can be added
+// without changing the response.
+async fn task_global_dependents(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<TaskDependentsRequest>(request).await?;
+    let app = read_state(&app_state);
+    let project = app.database.find_project_by_id(request.project_id)?;
+    project.find_task_by_id(request.task_id)?;
+    let dependents = project
+        .dependents_of(request.task_id)
+        .map(|task| GlobalTaskRef {
+            project_id: project.id,
+            task_id: task.id,
+            title: task.title.clone(),
+        })
+        .collect::<Vec<_>>();
+    Ok(Response::new(Body::from(
+        json!({ "dependents": dependents }).to_string(),
+    )))
+}
+
+// How much each factor can take off a project's health score. They add up to 100.
+const HEALTH_BLOCKED_WEIGHT: f64 = 30.0;
+const HEALTH_OVERDUE_WEIGHT: f64 = 30.0;
+const HEALTH_STALE_WEIGHT: f64 = 20.0;
+const HEALTH_WIP_WEIGHT: f64 = 20.0;
+// Open tasks with no log activity for this long are considered stale
+const STALE_TASK_DAYS: i64 = 14;
+// Tasks one assignee can have in progress at once before the rest count against the score
+const WIP_LIMIT_PER_ASSIGNEE: usize = 3;
+
+#[derive(Deserialize, Debug)]
+struct ProjectHealthRequest {
+    project_id: usize,
+}
+
+#[derive(Serialize, Debug)]
+struct HealthFactor {
+    name: &'static str,
+    weight: f64,
+    affected_tasks: usize,
+    penalty: f64,
+}
+
+impl HealthFactor {
+    fn new(name: &'static str, weight: f64, affected_tasks: usize, open_tasks: usize) -> Self {
+        let penalty = if open_tasks == 0 {
+            0.0
+        } else {
+            weight * affected_tasks as f64 / open_tasks as f64
+        };
+        HealthFactor {
+            name,
+            weight,
+            affected_tasks,
+            penalty,
+        }
+    }
+}
+
+async fn project_health(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<ProjectHealthRequest>(request).await?;
+    let app = read_state(&app_state);
+    let project = app.database.find_project_by_id(request.project_id)?;
+    let now = chrono::Utc::now();
+    let stale_before = now - chrono::Duration::days(STALE_TASK_DAYS);
+    let open_tasks = project
+        .tasks
+        .iter()
+        .filter(|task| !app.config.is_terminal(task.state))
+        .collect::<Vec<_>>();
+    let blocked = open_tasks
+        .iter()
+        .filter(|task| matches!(task.state, State::Blocked))
+        .count();
+    let stale = open_tasks
+        .iter()
+        .filter(|task| {
+            // A task which has never been touched has been stale since it was created. Tasks from
+            // before creation times were kept count as created at the epoch.
+            let last_activity = task
+                .log
+                .last()
+                .map_or(task.created_at, |entry| entry.timestamp);
+            last_activity < stale_before
+        })
+        .count();
+    let overdue = open_tasks
+        .iter()
+        .filter(|task| task.due_date.is_some_and(|due_date| due_date < now))
+        .count();
+    // Unassigned tasks aren't anyone's work in progress, so they're left out
+    let mut in_progress = HashMap::<&str, usize>::new();
+    for task in &open_tasks {
+        if let (State::InProgress, Some(assignee)) = (task.state, &task.assignee) {
+            *in_progress.entry(assignee.as_str()).or_default() += 1;
+        }
+    }
+    let over_wip_limit = in_progress
+        .values()
+        .map(|count| count.saturating_sub(WIP_LIMIT_PER_ASSIGNEE))
+        .sum();
+    let factors = vec![
+        HealthFactor::new("blocked", HEALTH_BLOCKED_WEIGHT, blocked, open_tasks.len()),
+        HealthFactor::new("overdue", HEALTH_OVERDUE_WEIGHT, overdue, open_tasks.len()),
+        HealthFactor::new("stale", HEALTH_STALE_WEIGHT, stale, open_tasks.len()),
+        HealthFactor::new("wip", HEALTH_WIP_WEIGHT, over_wip_limit, open_tasks.len()),
+    ];
+    let penalty = factors.iter().map(|factor| factor.penalty).sum::<f64>();
+    let score = (100.0 - penalty).clamp(0.0, 100.0).round() as u8;
+    Ok(Response::new(Body::from(
+        json!({
+            "score": score,
+            "open_tasks": open_tasks.len(),
+            "factors": factors,
+        })
+        .to_string(),
+    )))
+}
+
+#[derive(Deserialize, Debug)]
+struct PostExportProjectsRequest {
+    project_ids: Vec<usize>,
+}
+
+// The export is serialized one project at a time while holding the lock, and then sent as a
+// chunked body, so a large selection never needs to be in one contiguous buffer
+async fn post_export_projects(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostExportProjectsRequest>(request).await?;
+    let app = read_state(&app_state);
+    let mut chunks = vec![hyper::body::Bytes::from_static(b"{\"projects\":[")];
+    for (index, project_id) in request.project_ids.iter().enumerate() {
+        let project = app.database.find_project_by_id(*project_id)?;
+        let mut chunk = if index == 0 { Vec::new() } else { vec![b','] };
+        serde_json::to_writer(&mut chunk, project)?;
+        chunks.push(chunk.into());
+    }
+    chunks.push(format!("],\"next_project_id\":{}}}", app.database.next_project_id).into());
+    let stream = futures::stream::iter(chunks.into_iter().map(Ok::<_, std::io::Error>));
+    Ok(Response::new(Body::wrap_stream(stream)))
+}
+
+#[derive(Deserialize, Debug)]
+struct ProjectTagCoverageRequest {
+    project_id: usize,
+}
+
+async fn project_tag_coverage(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<ProjectTagCoverageRequest>(request).await?;
+    let app = read_state(&app_state);
+    let project = app.database.find_project_by_id(request.project_id)?;
+    let mut tag_counts = BTreeMap::<&str, usize>::new();
+    for tag in project.tasks.iter().flat_map(|task| task.tags.iter()) {
+        *tag_counts.entry(tag).or_default() += 1;
+    }
+    let total = project.tasks.len();
+    let untagged = project
+        .tasks
+        .iter()
+        .filter(|task| task.tags.is_empty())
+        .count();
+    let coverage = if total == 0 {
+        0.0
+    } else {
+        (total - untagged) as f64 / total as f64
+    };
+    Ok(Response::new(Body::from(
+        json!({
+            "total_tasks": total,
+            "untagged_tasks": untagged,
+            "coverage": coverage,
+            "tags": tag_counts,
+        })
+        .to_string(),
+    )))
+}
+
+#[derive(Deserialize, Debug)]
+struct PostAdminRecomputeIdsRequest {
+    #[serde(default)]
+    confirm: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct CounterChange {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    project_id: Option<usize>,
+    from: usize,
+    to: usize,
+}
+
+async fn post_admin_recompute_ids(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostAdminRecomputeIdsRequest>(request).await?;
+    if !request.confirm {
+        return Err(ApiError::InvalidValue(
+            "Recomputing IDs requires \"confirm\": true".to_owned(),
+        )
+        .into());
+    }
+    let mut app = write_state(&app_state);
+    // Counters are only ever raised. Lowering one would hand out the IDs of deleted tasks and
+    // projects again, which history, share tokens and webhook receivers may still refer to.
+    let mut task_counters = Vec::new();
+    for project in app.database.projects.values_mut() {
+        let next_task_id = project.tasks.iter().map(|task| task.id + 1).max();
+        let next_task_id = next_task_id.unwrap_or(0).max(project.next_task_id);
+        if project.next_task_id != next_task_id {
+            task_counters.push(CounterChange {
+                project_id: Some(project.id),
+                from: project.next_task_id,
+                to: next_task_id,
+            });
+            project.next_task_id = next_task_id;
+        }
+    }
+    let database = &mut app.database;
+    let next_project_id = database.projects.keys().map(|id| id + 1).max();
+    let next_project_id = next_project_id.unwrap_or(0).max(database.next_project_id);
+    let mut project_counter = None;
+    if database.next_project_id != next_project_id {
+        project_counter = Some(CounterChange {
+            project_id: None,
+            from: database.next_project_id,
+            to: next_project_id,
+        });
+        database.next_project_id = next_project_id;
+    }
+    if project_counter.is_some() || !task_counters.is_empty() {
+        app.record_mutation();
+    }
+    Ok(Response::new(Body::from(
+        json!({
+            "next_project_id": project_counter,
+            "next_task_ids": task_counters,
+        })
+        .to_string(),
+    )))
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+enum GroupByField {
+    State,
+    Priority,
+    Assignee,
+    Tag,
+}
+
+#[derive(Deserialize, Debug)]
+struct ProjectGroupByRequest {
+    project_id: usize,
+    field: GroupByField,
+}
+
+async fn project_group_by(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<ProjectGroupByRequest>(request).await?;
+    let app = read_state(&app_state);
+    let project = app.database.find_project_by_id(request.project_id)?;
+    let mut counts = BTreeMap::<String, usize>::new();
+    let mut unset = 0;
+    for task in &project.tasks {
+        let values = match request.field {
+            GroupByField::State => vec![format!("{:?}", task.state)],
+            GroupByField::Priority => vec![format!("{:?}", task.priority)],
+            GroupByField::Assignee => task.assignee.iter().cloned().collect(),
+            GroupByField::Tag => task.tags.iter().cloned().collect(),
+        };
+        if values.is_empty() {
+            unset += 1;
+        }
+        for value in values {
+            *counts.entry(value).or_default() += 1;
+        }
+    }
+    Ok(Response::new(Body::from(
+        json!({ "counts": counts, "unset": unset }).to_string(),
+    )))
+}
+
+#[derive(Deserialize, Debug)]
+struct ProjectReportRequest {
+    project_id: usize,
+}
+
+async fn project_report(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<ProjectReportRequest>(request).await?;
+    let app = read_state(&app_state);
+    let project = app.database.find_project_by_id(request.project_id)?;
+    let now = chrono::Utc::now();
+    let report = json!({
+        "project_id": project.id,
+        "name": project.name.clone(),
+        "generated_at": now.timestamp(),
+        "progress": metrics::progress(project, &app.config.terminal_states),
+        "throughput": metrics::throughput(project, now),
+        "cycle_time": metrics::cycle_time(project),
+        "aging": metrics::aging(project, now, &app.config.terminal_states),
+    });
+    let disposition = format!(
+        "attachment; filename=\"project-{}-report.json\"",
+        project.id
+    );
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CONTENT_DISPOSITION, disposition)
+        .body(Body::from(serde_json::to_string_pretty(&report)?))?)
+}
+
+#[derive(Deserialize, Debug)]
+struct ProjectExportCsvRequest {
+    project_id: usize,
+}
+
+// Quotes a CSV field if it holds anything which would otherwise break up the row
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+// Keeps a project name usable as a file name in a header, replacing anything unusual
+fn file_name_stem(name: &str, project_id: usize) -> String {
+    let stem = name
+        .trim()
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() || c == '-' || c == '_' => c,
+            _ => '_',
+        })
+        .collect::<String>();
+    if stem.chars().all(|c| c == '_') {
+        format!("project-{}", project_id)
+    } else {
+        stem
+    }
+}
+
+// Like the JSON export, rows are formatted while holding the lock and then sent a row at a time
+async fn project_export_csv(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<ProjectExportCsvRequest>(request).await?;
+    let app = read_state(&app_state);
+    let project = app.database.find_project_by_id(request.project_id)?;
+    let mut rows = vec!["task_id,title,state,priority,due_date,dependencies\r\n".to_owned()];
+    for task in &project.tasks {
+        let mut dependencies = task.dependencies.iter().copied().collect::<Vec<_>>();
+        dependencies.sort_unstable();
+        let dependencies = dependencies
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let due_date = task
+            .due_date
+            .map(|due_date| due_date.to_rfc3339())
+            .unwrap_or_default();
+        rows.push(format!(
+            "{},{},{:?},{:?},{},{}\r\n",
+            task.id,
+            csv_field(&task.title),
+            task.state,
+            task.priority,
+            due_date,
+            dependencies
+        ));
+    }
+    let disposition = format!(
+        "attachment; filename=\"{}.csv\"",
+        file_name_stem(&project.name, project.id)
+    );
+    let stream = futures::stream::iter(rows.into_iter().map(Ok::<_, std::io::Error>));
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "text/csv; charset=utf-8")
+        .header(header::CONTENT_DISPOSITION, disposition)
+        .body(Body::wrap_stream(stream))?)
+}
+
+#[derive(Default, Deserialize, Debug)]
+struct CalendarRequest {
+    project_id: Option<usize>,
+}
+
+async fn calendar(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_optional_body::<CalendarRequest>(request).await?;
+    let app = read_state(&app_state);
+    let projects = match request.project_id {
+        Some(project_id) => vec![app.database.find_project_by_id(project_id)?],
+        None => app.database.projects.values().collect(),
+    };
+    let tasks = projects.into_iter().flat_map(|project| {
+        project
+            .tasks
+            .iter()
+            .filter(|task| !task.archived)
+            .map(move |task| (project, task))
+    });
+    let feed = ical::render(tasks, chrono::Utc::now());
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "text/calendar; charset=utf-8")
+        .body(Body::from(feed))?)
+}
+
+#[derive(Deserialize, Debug)]
+struct PostImportRequest {
+    projects: Vec<Project>,
+    #[serde(default)]
+    validate_only: bool,
+}
+
+async fn post_import(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostImportRequest>(request).await?;
+    let mut app = write_state(&app_state);
+    let report = app
+        .database
+        .import_projects(request.projects, !request.validate_only);
+    if !request.validate_only {
+        if !report.applied {
+            return Err(ApiError::InvalidImport(report.errors).into());
+        }
+        app.record_mutation();
+    }
+    Ok(Response::new(Body::from(serde_json::to_string(&report)?)))
+}
+
+#[derive(Deserialize, Debug)]
+struct PostProjectImportRequest {
+    #[serde(flatten)]
+    project: Project,
+    #[serde(default)]
+    renumber_tasks: bool,
+}
+
+async fn post_project_import(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostProjectImportRequest>(request).await?;
+    let mut app = write_state(&app_state);
+    let project_id = app
+        .database
+        .import_project(request.project, request.renumber_tasks)?;
+    app.record_mutation();
+    created(
+        project_location(project_id),
+        json!({ "project_id": project_id }),
+    )
+}
+
+#[derive(Deserialize, Debug)]
+struct PostWebhooksRequest {
+    url: String,
+    events: BTreeSet<WebhookEvent>,
+}
+
+async fn post_webhooks(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostWebhooksRequest>(request).await?;
+    let url = request.url.trim();
+    let is_http = url
+        .parse::<hyper::Uri>()
+        .is_ok_and(|uri| uri.scheme_str() == Some("http") && uri.host().is_some());
+    if !is_http {
+        return Err(
+            ApiError::InvalidValue(format!("Webhook URL must be an http:// URL: {}", url)).into(),
+        );
+    }
+    if request.events.is_empty() {
+        return Err(ApiError::InvalidValue("Webhook events cannot be empty".to_owned()).into());
+    }
+    let mut app = write_state(&app_state);
+    let database = &mut app.database;
+    let webhook_id = database.next_webhook_id;
+    database.next_webhook_id += 1;
+    database.webhooks.insert(
+        webhook_id,
+        Webhook {
+            url: url.to_owned(),
+            events: request.events,
+        },
+    );
+    app.record_mutation();
+    created("/webhooks".to_owned(), json!({ "webhook_id": webhook_id }))
+}
+
+async fn list_webhooks(
+    _request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let app = read_state(&app_state);
+    let webhooks = app
+        .database
+        .webhooks
+        .iter()
+        .map(|(id, webhook)| json!({ "id": id, "url": webhook.url, "events": webhook.events }))
+        .collect::<Vec<_>>();
+    Ok(Response::new(Body::from(
+        json!({ "webhooks": webhooks }).to_string(),
+    )))
+}
+
+#[derive(Deserialize, Debug)]
+struct PostWebhooksDeleteRequest {
+    webhook_id: usize,
+}
+
+async fn post_webhooks_delete(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostWebhooksDeleteRequest>(request).await?;
+    let mut app = write_state(&app_state);
+    app.database
+        .webhooks
+        .remove(&request.webhook_id)
+        .ok_or(ApiError::WebhookNotFound(request.webhook_id))?;
+    app.record_mutation();
+    Ok(Response::new(Body::from(
+        json!({"status": 200, "description": "OK"}).to_string(),
+    )))
+}
+
+// A task as exported by some other tool. Anything beyond these fields is ignored.
+#[derive(Deserialize, Debug)]
+struct GenericTask {
+    title: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    state: Option<String>,
+    #[serde(default)]
+    priority: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+// Matches state names loosely, since other tools spell them differently. Anything unrecognised
+// becomes Todo.
+fn parse_generic_state(state: &str) -> State {
+    let normalized = state
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .collect::<String>()
+        .to_lowercase();
+    match normalized.as_str() {
+        "inprogress" | "doing" | "started" => State::InProgress,
+        "blocked" => State::Blocked,
+        "cancelled" | "canceled" => State::Cancelled,
+        "done" | "complete" | "completed" | "closed" => State::Done,
+        _ => State::Todo,
+    }
+}
+
+// Like states, priorities are matched loosely. Anything unrecognised is left at the default.
+fn parse_generic_priority(priority: &str) -> Priority {
+    let normalized = priority
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .collect::<String>()
+        .to_lowercase();
+    match normalized.as_str() {
+        "low" | "minor" | "trivial" => Priority::Low,
+        "high" | "major" | "important" => Priority::High,
+        "critical" | "urgent" | "blocker" | "highest" => Priority::Critical,
+        _ => Priority::default(),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct PostImportGenericRequest {
+    project_id: usize,
+    tasks: Vec<serde_json::Value>,
+}
+
+#[derive(Serialize, Debug)]
+struct GenericImportCreated {
+    index: usize,
+    task_id: usize,
+    #[serde(skip)]
+    title: String,
+}
+
+#[derive(Serialize, Debug)]
+struct GenericImportError {
+    index: usize,
+    error: String,
+}
+
+// Unlike POST /import, entries which can't be understood are skipped and reported, and the rest
+// are still imported
+async fn post_import_generic(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostImportGenericRequest>(request).await?;
+    let mut app = write_state(&app_state);
+    let max_log_length = app.config.max_log_length;
+    app.database.find_project_by_id(request.project_id)?;
+    let mut created = Vec::new();
+    let mut errors = Vec::new();
+    for (index, entry) in request.tasks.into_iter().enumerate() {
+        let generic = match serde_json::from_value::<GenericTask>(entry) {
+            Ok(generic) if generic.title.trim().is_empty() => {
+                errors.push(GenericImportError {
+                    index,
+                    error: "Title cannot be empty".to_owned(),
+                });
+                continue;
+            }
+            Ok(generic) => generic,
+            Err(error) => {
+                errors.push(GenericImportError {
+                    index,
+                    error: error.to_string(),
+                });
+                continue;
+            }
+        };
+        let project = app.database.find_project_by_id_mut(request.project_id)?;
+        let task_id = project.create_task(generic.title.clone(), generic.description);
+        let task = project.find_task_by_id_mut(task_id)?;
+        task.tags = generic
+            .tags
+            .iter()
+            .map(|tag| normalize_tag(tag))
+            .filter(|tag| !tag.is_empty())
+            .collect();
+        let priority = generic
+            .priority
+            .as_deref()
+            .map_or(Priority::default(), parse_generic_priority);
+        if priority != task.priority {
+            task.new_log_entry(LogEntryType::PriorityChangedTo(priority), max_log_length);
+            task.priority = priority;
+        }
+        let state = generic
+            .state
+            .as_deref()
+            .map_or(State::Todo, parse_generic_state);
+        if state != State::Todo {
+            task.new_log_entry(LogEntryType::StateChangedTo(state), max_log_length);
+            task.state = state;
+        }
+        app.database.record_history(
+            HistoryEventKind::TaskCreated,
+            request.project_id,
+            Some(task_id),
+            &generic.title,
+        );
+        created.push(GenericImportCreated {
+            index,
+            task_id,
+            title: generic.title,
+        });
+    }
+    if !created.is_empty() {
+        app.record_mutation();
+    }
+    for entry in &created {
+        app.database
+            .notify_task_created(request.project_id, entry.task_id, &entry.title);
+    }
+    Ok(Response::new(Body::from(
+        json!({ "created": created, "errors": errors }).to_string(),
+    )))
+}
+
+#[derive(Deserialize, Debug)]
+struct PostAdminSnapshotRequest {
+    name: String,
+}
+
+// Saving under an existing name replaces that snapshot
+async fn post_admin_snapshot(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostAdminSnapshotRequest>(request).await?;
+    // Writes to the same snapshot would otherwise share its temporary file
+    let flush_lock = read_state(&app_state).flush_lock.clone();
+    let _writing = flush_lock.lock().await;
+    let (snapshot_path, contents) = {
+        let app = read_state(&app_state);
+        (
+            app.get_snapshot_path(&request.name)?,
+            app.serialize_database()?,
+        )
+    };
+    write_database_file(snapshot_path, contents).await?;
+    Ok(Response::new(Body::from(
+        json!({"status": 200, "description": "OK"}).to_string(),
+    )))
+}
+
+#[derive(Deserialize, Debug)]
+struct PostAdminRestoreRequest {
+    name: String,
+}
+
+// The database being replaced is saved as a snapshot of its own first, so a restore can be undone
+async fn post_admin_restore(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostAdminRestoreRequest>(request).await?;
+    let snapshot_path = read_state(&app_state).get_snapshot_path(&request.name)?;
+    let database = AppState::read_database(&snapshot_path)
+        .await
+        .map_err(|error| format!("Could not read snapshot {}: {}", request.name, error))?;
+    let backup_name = format!("before-restore-{}", chrono::Utc::now().timestamp());
+    let backup_path = read_state(&app_state).get_snapshot_path(&backup_name)?;
+    // The backup is written without the state lock, so it's written again if anything changed in
+    // the meantime, and only then is the database replaced
+    let flush_lock = read_state(&app_state).flush_lock.clone();
+    let writing = flush_lock.lock().await;
+    loop {
+        let (revision, contents) = {
+            let app = read_state(&app_state);
+            (app.database.revision, app.serialize_database()?)
+        };
+        write_database_file(backup_path.clone(), contents).await?;
+        let mut app = write_state(&app_state);
+        if app.database.revision == revision {
+            app.database = database;
+            // Carry on from the current revision, so clients holding the snapshot's revision still
+            // see that something changed
+            app.database.revision = revision + 1;
+            break;
+        }
+    }
+    // Taken again by flush_database
+    drop(writing);
+    flush_database(&app_state).await?;
+    Ok(Response::new(Body::from(
+        json!({"status": 200, "description": "OK", "backup": backup_name}).to_string(),
+    )))
+}
+
+#[derive(Serialize, Debug)]
+struct SnapshotInfo {
+    name: String,
+    size_bytes: u64,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    modified: chrono::DateTime<chrono::Utc>,
+}
+
+// Reports how far the database on disk lags behind the one in memory
+async fn admin_flush_status(
+    _request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let app = read_state(&app_state);
+    let response = json!({
+        "clean": app.pending_mutations == 0,
+        "pending_mutations": app.pending_mutations,
+        "last_successful_flush": app
+            .last_successful_flush
+            .map(|timestamp| timestamp.timestamp()),
+        "consecutive_flush_failures": app.consecutive_flush_failures,
+        "flush_deferred": app.flush_deferred,
+    });
+    Ok(Response::new(Body::from(response.to_string())))
+}
+
+async fn admin_snapshots(
+    _request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let mut snapshots = Vec::new();
+    let snapshot_dir = read_state(&app_state).get_snapshot_dir();
+    let mut entries = match tokio::fs::read_dir(snapshot_dir).await {
+        Ok(entries) => entries,
+        // No snapshot has been taken yet
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(Response::new(Body::from(
+                json!({ "snapshots": snapshots }).to_string(),
+            )))
+        }
+        Err(error) => return Err(error.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().and_then(|extension| extension.to_str()) != Some("json") {
+            continue;
+        }
+        let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(name) => name.to_owned(),
+            None => continue,
+        };
+        let metadata = tokio::fs::metadata(&path).await?;
+        snapshots.push(SnapshotInfo {
+            name,
+            size_bytes: metadata.len(),
+            modified: metadata.modified()?.into(),
+        });
+    }
+    snapshots.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(Response::new(Body::from(
+        json!({ "snapshots": snapshots }).to_string(),
+    )))
+}
+
+#[derive(Default, Deserialize, Debug)]
+struct AdminHistoryRequest {
+    #[serde(default, with = "chrono::serde::ts_seconds_option")]
+    since: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default, with = "chrono::serde::ts_seconds_option")]
+    until: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+async fn admin_history(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_optional_body::<AdminHistoryRequest>(request).await?;
+    let app = read_state(&app_state);
+    let events = app
+        .database
+        .history
+        .iter()
+        .filter(|event| request.since.is_none_or(|since| event.timestamp >= since))
+        .filter(|event| request.until.is_none_or(|until| event.timestamp < until))
+        .collect::<Vec<_>>();
+    Ok(Response::new(Body::from(
+        json!({ "events": events }).to_string(),
+    )))
+}
+
+// Levenshtein distance, counting characters rather than bytes
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+// Finds the root of an element in a union-find forest, flattening the path along the way
+fn root(parents: &mut [usize], mut index: usize) -> usize {
+    while parents[index] != index {
+        parents[index] = parents[parents[index]];
+        index = parents[index];
+    }
+    index
+}
+
+fn normalize_tag(tag: &str) -> String {
+    tag.trim().to_lowercase()
+}
+
+const DEFAULT_TAG_DISTANCE: usize = 1;
+
+fn default_tag_distance() -> usize {
+    DEFAULT_TAG_DISTANCE
+}
+
+#[derive(Deserialize, Debug)]
+struct TagsSimilarRequest {
+    #[serde(default = "default_tag_distance")]
+    max_distance: usize,
+}
+
+impl Default for TagsSimilarRequest {
+    fn default() -> TagsSimilarRequest {
+        TagsSimilarRequest {
+            max_distance: DEFAULT_TAG_DISTANCE,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct TagUsage {
+    tag: String,
+    task_count: usize,
+}
+
+// Groups tags which are within max_distance edits of each other once normalized, including
+// through other tags in the group
+async fn tags_similar(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_optional_body::<TagsSimilarRequest>(request).await?;
+    let app = read_state(&app_state);
+    let mut counts = BTreeMap::<&str, usize>::new();
+    for task in app
+        .database
+        .projects
+        .values()
+        .flat_map(|project| project.tasks.iter())
+    {
+        for tag in &task.tags {
+            *counts.entry(tag).or_default() += 1;
+        }
+    }
+    let tags = counts.into_iter().collect::<Vec<_>>();
+    let normalized = tags
+        .iter()
+        .map(|(tag, _)| normalize_tag(tag))
+        .collect::<Vec<_>>();
+    // Union-find over tag indices
+    let mut parents = (0..tags.len()).collect::<Vec<_>>();
+    for i in 0..tags.len() {
+        for j in i + 1..tags.len() {
+            if edit_distance(&normalized[i], &normalized[j]) <= request.max_distance {
+                let (root_i, root_j) = (root(&mut parents, i), root(&mut parents, j));
+                parents[root_j] = root_i;
+            }
+        }
+    }
+    let mut clusters = BTreeMap::<usize, Vec<TagUsage>>::new();
+    for (index, (tag, task_count)) in tags.iter().enumerate() {
+        clusters
+            .entry(root(&mut parents, index))
+            .or_default()
+            .push(TagUsage {
+                tag: tag.to_string(),
+                task_count: *task_count,
+            });
+    }
+    let clusters = clusters
+        .into_values()
+        .filter(|cluster| cluster.len() > 1)
+        .map(|mut cluster| {
+            cluster.sort_by_key(|usage| std::cmp::Reverse(usage.task_count));
+            json!({ "tags": cluster })
+        })
+        .collect::<Vec<_>>();
+    Ok(Response::new(Body::from(
+        json!({ "clusters": clusters }).to_string(),
+    )))
+}
 
-async fn post_project_description(
+#[derive(Deserialize, Debug)]
+struct PostTagsMergeRequest {
+    from: Vec<String>,
+    into: String,
+}
+
+async fn post_tags_merge(
     request: Request<Body>,
-    app_state: Arc<Mutex<AppState>>,
+    app_state: Arc<RwLock<AppState>>,
 ) -> Result<Response<Body>, Box<dyn std::error::Error>> {
-    let full_body = hyper::body::to_bytes(request.into_body()).await?;
-    let request = serde_json::from_slice::<PostProjectDescriptionRequest>(&full_body)?;
-    let mut app = app_state.lock().unwrap();
-    let project = app.database.find_project_by_id_mut(request.project_id)?;
-    project.description = request.description;
-    app.flush()?;
+    let request = parse_body::<PostTagsMergeRequest>(request).await?;
+    let into = normalize_tag(&request.into);
+    if into.is_empty() {
+        return Err(ApiError::InvalidValue("Tag cannot be empty".to_owned()).into());
+    }
+    let from = request
+        .from
+        .iter()
+        .map(|tag| normalize_tag(tag))
+        .collect::<HashSet<_>>();
+    let mut app = write_state(&app_state);
+    let mut affected_tasks = 0;
+    for task in app
+        .database
+        .projects
+        .values_mut()
+        .flat_map(|project| project.tasks.iter_mut())
+    {
+        // Stored tags are normalized too, since older databases may hold tags which aren't
+        let is_merged = |tag: &String| from.contains(&normalize_tag(tag));
+        if !task.tags.iter().any(is_merged) {
+            continue;
+        }
+        let mut merged = task
+            .tags
+            .iter()
+            .filter(|tag| !is_merged(tag))
+            .cloned()
+            .collect::<HashSet<_>>();
+        merged.insert(into.clone());
+        // A task which only had the tag being merged into is left as it was
+        if merged != task.tags {
+            task.tags = merged;
+            task.version += 1;
+            affected_tasks += 1;
+        }
+    }
+    if affected_tasks > 0 {
+        app.record_mutation();
+    }
     Ok(Response::new(Body::from(
-        json!({"status": 200, "description": "OK"}).to_string(),
+        json!({"status": 200, "description": "OK", "affected_tasks": affected_tasks}).to_string(),
     )))
 }
 
 #[derive(Deserialize, Debug)]
-struct TaskDetailsRequest {
+struct AssigneeProjectsRequest {
+    assignee: String,
+}
+
+#[derive(Serialize, Debug)]
+struct AssigneeInvolvement {
     project_id: usize,
-    task_id: usize,
+    name: String,
+    task_count: usize,
 }
 
-async fn task_details(
+async fn assignee_projects(
     request: Request<Body>,
-    app_state: Arc<Mutex<AppState>>,
+    app_state: Arc<RwLock<AppState>>,
 ) -> Result<Response<Body>, Box<dyn std::error::Error>> {
-    let full_body = hyper::body::to_bytes(request.into_body()).await?;
-    let request = serde_json::from_slice::<TaskDetailsRequest>(&full_body)?;
-    let app = app_state.lock().unwrap();
-    let project = app.database.find_project_by_id(request.project_id)?;
-    let task = project.find_task_by_id(request.task_id)?;
-    Ok(Response::new(Body::from(serde_json::to_string(task)?)))
+    let request = parse_body::<AssigneeProjectsRequest>(request).await?;
+    let app = read_state(&app_state);
+    let mut projects = app
+        .database
+        .projects
+        .values()
+        .map(|project| AssigneeInvolvement {
+            project_id: project.id,
+            name: project.name.clone(),
+            task_count: project
+                .tasks
+                .iter()
+                .filter(|task| task.assignee.as_ref() == Some(&request.assignee))
+                .count(),
+        })
+        .filter(|involvement| involvement.task_count > 0)
+        .collect::<Vec<_>>();
+    projects.sort_by_key(|involvement| std::cmp::Reverse(involvement.task_count));
+    Ok(Response::new(Body::from(
+        json!({ "projects": projects }).to_string(),
+    )))
+}
+
+// A filter over tasks across the whole database. Every criterion which is set must match.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct TaskQuery {
+    #[serde(default)]
+    project_ids: Option<Vec<usize>>,
+    // Matches tasks in any of these states
+    #[serde(default)]
+    states: Option<Vec<State>>,
+    // Matches tasks with any of these priorities
+    #[serde(default)]
+    priorities: Option<Vec<Priority>>,
+    // Matches tasks with at least this priority, going Low, Medium, High, Critical. Unlike
+    // priorities, this doesn't have to be changed when a new priority is added above it.
+    #[serde(default)]
+    priority_min: Option<Priority>,
+    // Matches tasks which have all of these tags
+    #[serde(default)]
+    tags: Option<Vec<String>>,
+    #[serde(default)]
+    assignee: Option<String>,
+    #[serde(default, with = "chrono::serde::ts_seconds_option")]
+    due_before: Option<chrono::DateTime<chrono::Utc>>,
+    // Case-insensitive substring of the title or description
+    #[serde(default)]
+    text: Option<String>,
+}
+
+impl TaskQuery {
+    fn matches(&self, project: &Project, task: &Task) -> bool {
+        if let Some(project_ids) = &self.project_ids {
+            if !project_ids.contains(&project.id) {
+                return false;
+            }
+        }
+        if let Some(states) = &self.states {
+            if !states.contains(&task.state) {
+                return false;
+            }
+        }
+        if let Some(priorities) = &self.priorities {
+            if !priorities.contains(&task.priority) {
+                return false;
+            }
+        }
+        if let Some(priority_min) = self.priority_min {
+            if task.priority < priority_min {
+                return false;
+            }
+        }
+        if let Some(tags) = &self.tags {
+            // The query's tags were normalized by run_task_query. Stored tags are normalized too,
+            // since older databases may hold tags which aren't.
+            let has_tag = |tag: &String| {
+                task.tags
+                    .iter()
+                    .any(|task_tag| normalize_tag(task_tag) == *tag)
+            };
+            if !tags.iter().all(has_tag) {
+                return false;
+            }
+        }
+        if self.assignee.is_some() && task.assignee != self.assignee {
+            return false;
+        }
+        if let Some(due_before) = self.due_before {
+            match task.due_date {
+                Some(due_date) if due_date < due_before => {}
+                _ => return false,
+            }
+        }
+        if let Some(text) = &self.text {
+            let text = text.to_lowercase();
+            if !task.title.to_lowercase().contains(&text)
+                && !task.description.to_lowercase().contains(&text)
+            {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+const DEFAULT_QUERY_LIMIT: usize = 50;
+const MAX_QUERY_LIMIT: usize = 500;
+
+fn default_query_limit() -> usize {
+    DEFAULT_QUERY_LIMIT
+}
+
+fn check_limit(limit: usize) -> Result<(), ApiError> {
+    if limit > MAX_QUERY_LIMIT {
+        return Err(ApiError::InvalidValue(format!(
+            "limit can be at most {}",
+            MAX_QUERY_LIMIT
+        )));
+    }
+    Ok(())
 }
 
 #[derive(Deserialize, Debug)]
-struct PostTaskStateChange {
+struct TasksQueryRequest {
+    #[serde(flatten)]
+    query: TaskQuery,
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "default_query_limit")]
+    limit: usize,
+}
+
+#[derive(Serialize, Debug)]
+struct TaskQueryMatch {
     project_id: usize,
-    task_id: usize,
-    new_state: State,
+    project_name: String,
+    task: TaskPeek,
 }
 
-async fn post_task_state(
+fn run_task_query(
+    database: &Database,
+    query: &TaskQuery,
+    offset: usize,
+    limit: usize,
+) -> Result<serde_json::Value, ApiError> {
+    // Once per query, rather than for every task
+    let mut query = query.clone();
+    if let Some(tags) = &mut query.tags {
+        for tag in tags.iter_mut() {
+            *tag = normalize_tag(tag);
+        }
+    }
+    find_tasks(database, offset, limit, |project, task| {
+        query.matches(project, task)
+    })
+}
+
+// Lists one page of the tasks across all projects which match the predicate, ordered by project ID
+// and then task ID
+fn find_tasks(
+    database: &Database,
+    offset: usize,
+    limit: usize,
+    matches: impl Fn(&Project, &Task) -> bool,
+) -> Result<serde_json::Value, ApiError> {
+    check_limit(limit)?;
+    let mut total = 0;
+    let mut tasks = Vec::new();
+    for project in database.projects.values() {
+        for task in &project.tasks {
+            if !matches(project, task) {
+                continue;
+            }
+            if total >= offset && tasks.len() < limit {
+                tasks.push(TaskQueryMatch {
+                    project_id: project.id,
+                    project_name: project.name.clone(),
+                    task: TaskPeek::new(task),
+                });
+            }
+            total += 1;
+        }
+    }
+    Ok(json!({
+        "total": total,
+        "offset": offset,
+        "tasks": tasks,
+    }))
+}
+
+async fn post_tasks_query(
     request: Request<Body>,
-    app_state: Arc<Mutex<AppState>>,
+    app_state: Arc<RwLock<AppState>>,
 ) -> Result<Response<Body>, Box<dyn std::error::Error>> {
-    let full_body = hyper::body::to_bytes(request.into_body()).await?;
-    let request = serde_json::from_slice::<PostTaskStateChange>(&full_body)?;
-    let mut app = app_state.lock().unwrap();
-    let project = app.database.find_project_by_id_mut(request.project_id)?;
-    let task = project.find_task_by_id_mut(request.task_id)?;
-    task.new_log_entry(LogEntryType::StateChangedTo(request.new_state));
-    task.state = request.new_state;
-    app.flush()?;
+    let request = parse_body::<TasksQueryRequest>(request).await?;
+    let app = read_state(&app_state);
+    let response = run_task_query(&app.database, &request.query, request.offset, request.limit)?;
+    Ok(Response::new(Body::from(response.to_string())))
+}
+
+#[derive(Deserialize, Debug)]
+struct PostQuerySaveRequest {
+    name: String,
+    query: TaskQuery,
+}
+
+// Saving under an existing name replaces that query
+async fn post_query_save(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<PostQuerySaveRequest>(request).await?;
+    let name = request.name.trim();
+    if name.is_empty() {
+        return Err(ApiError::InvalidValue("Query name cannot be empty".to_owned()).into());
+    }
+    let mut app = write_state(&app_state);
+    let replaced = app
+        .database
+        .saved_queries
+        .insert(name.to_owned(), request.query)
+        .is_some();
+    app.record_mutation();
     Ok(Response::new(Body::from(
-        json!({"status": 200, "description": "OK"}).to_string(),
+        json!({"status": 200, "description": "OK", "replaced": replaced}).to_string(),
     )))
 }
 
 #[derive(Deserialize, Debug)]
-struct PostTaskDeleteRequest {
-    project_id: usize,
-    task_id: usize,
+struct QueryRunRequest {
+    name: String,
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "default_query_limit")]
+    limit: usize,
 }
 
-async fn post_task_delete(
+async fn query_run(
     request: Request<Body>,
-    app_state: Arc<Mutex<AppState>>,
+    app_state: Arc<RwLock<AppState>>,
 ) -> Result<Response<Body>, Box<dyn std::error::Error>> {
-    let full_body = hyper::body::to_bytes(request.into_body()).await?;
-    let request = serde_json::from_slice::<PostTaskDeleteRequest>(&full_body)?;
-    let mut app = app_state.lock().unwrap();
-    let project = app.database.find_project_by_id_mut(request.project_id)?;
-    project.remove_task(request.task_id)?;
-    app.flush()?;
+    let request = parse_body::<QueryRunRequest>(request).await?;
+    let app = read_state(&app_state);
+    let query = app
+        .database
+        .saved_queries
+        .get(request.name.trim())
+        .ok_or_else(|| ApiError::QueryNotFound(request.name.clone()))?;
+    let response = run_task_query(&app.database, query, request.offset, request.limit)?;
+    Ok(Response::new(Body::from(response.to_string())))
+}
+
+async fn query_list(
+    _request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let app = read_state(&app_state);
+    let queries = app
+        .database
+        .saved_queries
+        .iter()
+        .map(|(name, query)| json!({ "name": name, "query": query }))
+        .collect::<Vec<_>>();
     Ok(Response::new(Body::from(
-        json!({"status": 200, "description": "OK"}).to_string(),
+        json!({ "queries": queries }).to_string(),
     )))
 }
 
 #[derive(Deserialize, Debug)]
-struct PostTaskCommentRequest {
-    project_id: usize,
-    task_id: usize,
-    comment: String,
+struct PostQueryDeleteRequest {
+    name: String,
 }
 
-async fn post_task_comment(
+async fn post_query_delete(
     request: Request<Body>,
-    app_state: Arc<Mutex<AppState>>,
+    app_state: Arc<RwLock<AppState>>,
 ) -> Result<Response<Body>, Box<dyn std::error::Error>> {
-    let full_body = hyper::body::to_bytes(request.into_body()).await?;
-    let request = serde_json::from_slice::<PostTaskCommentRequest>(&full_body)?;
-    let mut app = app_state.lock().unwrap();
-    let project = app.database.find_project_by_id_mut(request.project_id)?;
-    let task = project.find_task_by_id_mut(request.task_id)?;
-    task.new_log_entry(LogEntryType::Comment(request.comment));
-    app.flush()?;
+    let request = parse_body::<PostQueryDeleteRequest>(request).await?;
+    let mut app = write_state(&app_state);
+    let removed = app
+        .database
+        .saved_queries
+        .remove(request.name.trim())
+        .is_some();
+    if removed {
+        app.record_mutation();
+    }
     Ok(Response::new(Body::from(
-        json!({"status": 200, "description": "OK"}).to_string(),
+        json!({"status": 200, "description": "OK", "already_absent": !removed}).to_string(),
     )))
 }
 
 #[derive(Deserialize, Debug)]
-struct PostTaskCreateRequest {
+struct TagsTasksRequest {
+    tag: String,
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "default_query_limit")]
+    limit: usize,
+}
+
+async fn tags_tasks(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let request = parse_body::<TagsTasksRequest>(request).await?;
+    let tag = normalize_tag(&request.tag);
+    let app = read_state(&app_state);
+    let response = find_tasks(&app.database, request.offset, request.limit, |_, task| {
+        task.tags
+            .iter()
+            .any(|task_tag| normalize_tag(task_tag) == tag)
+    })?;
+    Ok(Response::new(Body::from(response.to_string())))
+}
+
+#[derive(Deserialize, Debug)]
+struct TasksByTagRequest {
     project_id: usize,
-    title: String,
-    description: String,
+    tag: String,
 }
 
-async fn post_task_create(
+// Lists a project's tasks with the tag, matched the same way tags are normalized
+async fn tasks_by_tag(
     request: Request<Body>,
-    app_state: Arc<Mutex<AppState>>,
+    app_state: Arc<RwLock<AppState>>,
 ) -> Result<Response<Body>, Box<dyn std::error::Error>> {
-    let full_body = hyper::body::to_bytes(request.into_body()).await?;
-    let request = serde_json::from_slice::<PostTaskCreateRequest>(&full_body)?;
-    let mut app = app_state.lock().unwrap();
-    let project = app.database.find_project_by_id_mut(request.project_id)?;
-    let task_id = project.create_task(request.title, request.description);
-    app.flush()?;
+    let request = parse_body::<TasksByTagRequest>(request).await?;
+    let tag = normalize_tag(&request.tag);
+    let app = read_state(&app_state);
+    let project = app.database.find_project_by_id(request.project_id)?;
+    let tasks = project
+        .tasks
+        .iter()
+        .filter(|task| {
+            task.tags
+                .iter()
+                .any(|task_tag| normalize_tag(task_tag) == tag)
+        })
+        .map(TaskPeek::new)
+        .collect::<Vec<_>>();
+    Ok(Response::new(Body::from(
+        json!({ "tasks": tasks }).to_string(),
+    )))
+}
+
+#[derive(Serialize, Debug)]
+struct OverdueTask {
+    project_id: usize,
+    task_id: usize,
+    title: String,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    due_date: chrono::DateTime<chrono::Utc>,
+    // Whole days since the due date
+    days_overdue: i64,
+}
+
+// Lists the open tasks across all projects which are past their due date, most overdue first
+async fn tasks_overdue(
+    _request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let now = chrono::Utc::now();
+    let app = read_state(&app_state);
+    let mut tasks = Vec::new();
+    for project in app.database.projects.values() {
+        for task in &project.tasks {
+            if task.archived || app.config.is_terminal(task.state) {
+                continue;
+            }
+            match task.due_date {
+                Some(due_date) if due_date < now => tasks.push(OverdueTask {
+                    project_id: project.id,
+                    task_id: task.id,
+                    title: task.title.clone(),
+                    due_date,
+                    days_overdue: (now - due_date).num_days(),
+                }),
+                _ => {}
+            }
+        }
+    }
+    // Stable, so equally overdue tasks stay ordered by project and task ID
+    tasks.sort_by_key(|task| task.due_date);
     Ok(Response::new(Body::from(
-        json!({ "task_id": task_id }).to_string(),
+        json!({ "tasks": tasks }).to_string(),
     )))
 }
 
 #[derive(Deserialize, Debug)]
-struct PostTaskTitleRequest {
+struct SearchRequest {
+    query: String,
+    #[serde(default)]
+    include_comments: bool,
+    #[serde(default)]
+    include_archived: bool,
+    #[serde(default = "default_query_limit")]
+    limit: usize,
+}
+
+// Where a search matched a task. Earlier fields count as more relevant.
+#[derive(Serialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+enum SearchField {
+    Title,
+    Description,
+    Comment,
+}
+
+#[derive(Serialize, Debug)]
+struct SearchResult {
     project_id: usize,
     task_id: usize,
     title: String,
+    matched_in: SearchField,
+    snippet: String,
 }
 
-async fn post_task_title(
+// Number of characters kept on either side of a match in its snippet
+const SNIPPET_CONTEXT: usize = 30;
+
+// Finds the first place the needle appears in the text, comparing characters without regard to
+// case. Returns the range of bytes it covers in the text.
+fn find_ignoring_case(text: &str, needle: &str) -> Option<std::ops::Range<usize>> {
+    let needle = needle.chars().collect::<Vec<_>>();
+    text.char_indices().find_map(|(start, _)| {
+        let mut chars = text[start..].char_indices();
+        for wanted in &needle {
+            let (_, c) = chars.next()?;
+            if !c.to_lowercase().eq(wanted.to_lowercase()) {
+                return None;
+            }
+        }
+        let end = chars
+            .next()
+            .map_or(text.len(), |(offset, _)| start + offset);
+        Some(start..end)
+    })
+}
+
+// The match along with some text either side of it, marking where the text was cut short
+fn snippet(text: &str, range: std::ops::Range<usize>) -> String {
+    let start = text[..range.start]
+        .char_indices()
+        .rev()
+        .nth(SNIPPET_CONTEXT - 1)
+        .map_or(0, |(index, _)| index);
+    let end = text[range.end..]
+        .char_indices()
+        .nth(SNIPPET_CONTEXT)
+        .map_or(text.len(), |(index, _)| range.end + index);
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push_str("...");
+    }
+    snippet.push_str(&text[start..end]);
+    if end < text.len() {
+        snippet.push_str("...");
+    }
+    snippet
+}
+
+// The most relevant place the query matches the task, if it does at all
+fn search_task(task: &Task, query: &str, include_comments: bool) -> Option<(SearchField, String)> {
+    let fields = [
+        (SearchField::Title, task.title.as_str()),
+        (SearchField::Description, task.description.as_str()),
+    ];
+    let comments = task
+        .log
+        .iter()
+        .filter(|_| include_comments)
+        .filter_map(|entry| match &entry.entry_type {
+            LogEntryType::Comment(comment) => Some((SearchField::Comment, comment.as_str())),
+            _ => None,
+        });
+    fields
+        .into_iter()
+        .chain(comments)
+        .find_map(|(field, text)| {
+            let range = find_ignoring_case(text, query)?;
+            Some((field, snippet(text, range)))
+        })
+}
+
+// Finds tasks across all projects whose title or description, and optionally comments, contain
+// the query. Title matches come first, then description matches, then comment matches.
+async fn search(
     request: Request<Body>,
-    app_state: Arc<Mutex<AppState>>,
+    app_state: Arc<RwLock<AppState>>,
 ) -> Result<Response<Body>, Box<dyn std::error::Error>> {
-    let full_body = hyper::body::to_bytes(request.into_body()).await?;
-    let request = serde_json::from_slice::<PostTaskTitleRequest>(&full_body)?;
-    let mut app = app_state.lock().unwrap();
-    let project = app.database.find_project_by_id_mut(request.project_id)?;
-    let task = project.find_task_by_id_mut(request.task_id)?;
-    task.title = request.title;
-    app.flush()?;
+    let request = parse_body::<SearchRequest>(request).await?;
+    let query = request.query.trim();
+    if query.is_empty() {
+        return Err(ApiError::InvalidValue("query can't be empty".to_owned()).into());
+    }
+    check_limit(request.limit)?;
+    let app = read_state(&app_state);
+    let mut results = Vec::new();
+    for project in app.database.projects.values() {
+        for task in &project.tasks {
+            if task.archived && !request.include_archived {
+                continue;
+            }
+            if let Some((matched_in, snippet)) = search_task(task, query, request.include_comments)
+            {
+                results.push(SearchResult {
+                    project_id: project.id,
+                    task_id: task.id,
+                    title: task.title.clone(),
+                    matched_in,
+                    snippet,
+                });
+            }
+        }
+    }
+    // Stable, so results for the same field stay ordered by project and task ID
+    results.sort_by_key(|result| result.matched_in);
+    let total = results.len();
+    results.truncate(request.limit);
     Ok(Response::new(Body::from(
-        json!({"status": 200, "description": "OK"}).to_string(),
+        json!({ "total": total, "results": results }).to_string(),
     )))
 }
 
+fn wrap_error(
+    inner: Result<Response<Body>, Box<dyn std::error::Error>>,
+) -> Result<Response<Body>, hyper::Error> {
+    match inner {
+        Ok(response) => Ok(response),
+        Err(error) => {
+            let (status, code) = match error.downcast_ref::<ApiError>() {
+                Some(api_error) => (api_error.status(), api_error.code()),
+                None => (StatusCode::INTERNAL_SERVER_ERROR, "INTERNAL_ERROR"),
+            };
+            let response_body = json!({
+                "status": status.as_u16(),
+                "code": code,
+                "description": error.to_string(),
+            })
+            .to_string();
+            Ok(Response::builder()
+                .status(status)
+                .body(Body::from(response_body))
+                .expect("Failed to build request"))
+        }
+    }
+}
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(0);
+
+// Marks the response to a request for a path which no endpoint handles
+#[derive(Clone, Copy)]
+struct UnknownRoute;
+
+async fn metrics_endpoint(
+    _request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error>> {
+    let app = read_state(&app_state);
+    // Every state is listed, so each series exists even while no task is in that state
+    let mut tasks_by_state = [
+        State::Todo,
+        State::InProgress,
+        State::Blocked,
+        State::Cancelled,
+        State::Done,
+    ]
+    .map(|state| (state, 0));
+    for task in app
+        .database
+        .projects
+        .values()
+        .flat_map(|project| project.tasks.iter())
+    {
+        if let Some((_, count)) = tasks_by_state
+            .iter_mut()
+            .find(|(state, _)| *state == task.state)
+        {
+            *count += 1;
+        }
+    }
+    let output = prometheus::render(app.database.projects.len(), &tasks_by_state);
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Body::from(output))?)
+}
+
+// Answers a CORS preflight for any path, without checking that the path exists. Browsers only
+// send the actual request once this allows it.
+fn preflight(request: &Request<Body>) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::NO_CONTENT;
+    let headers = response.headers_mut();
+    headers.insert(
+        header::ACCESS_CONTROL_ALLOW_METHODS,
+        header::HeaderValue::from_static("GET, POST, OPTIONS"),
+    );
+    // Nothing here depends on which headers are sent, so any the browser asks for are allowed
+    if let Some(requested) = request
+        .headers()
+        .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+    {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, requested.clone());
+    }
+    headers.insert(
+        header::ACCESS_CONTROL_MAX_AGE,
+        header::HeaderValue::from_static("86400"),
+    );
+    response
+}
+
+// Response headers which scripts on other origins may read, besides the ones always allowed
+const CORS_EXPOSED_HEADERS: &str = "etag, location, server-timing, x-request-id";
+
+async fn request_handler(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+    database_path: Arc<PathBuf>,
+    cors_origin: header::HeaderValue,
+) -> Result<Response<Body>, hyper::Error> {
+    // Numbered from startup, unless the client picked its own ID
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .map(str::to_owned)
+        .unwrap_or_else(|| NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed).to_string());
+    let span = tracing::info_span!(
+        "request",
+        method = %request.method(),
+        path = request.uri().path(),
+        request_id = request_id.as_str(),
+    );
+    let method = request.method().to_string();
+    let path = request.uri().path().to_owned();
+    async move {
+        let is_preflight = request.method() == Method::OPTIONS;
+        let mut response = if is_preflight {
+            preflight(&request)
+        } else {
+            handle_request(request, app_state, database_path).await?
+        };
+        // Paths which don't exist are lumped together, so clients can't add metrics at will.
+        // Preflights are answered for any path, so they are lumped together too.
+        let path = match response.extensions().get::<UnknownRoute>() {
+            Some(_) => "unknown",
+            None if is_preflight => "*",
+            None => &path,
+        };
+        let headers = response.headers_mut();
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, cors_origin);
+        if !is_preflight {
+            headers.insert(
+                header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                header::HeaderValue::from_static(CORS_EXPOSED_HEADERS),
+            );
+        }
+        prometheus::SERVER_METRICS.record_request(&method, path);
+        if let Ok(request_id) = header::HeaderValue::from_str(&request_id) {
+            response.headers_mut().insert(
+                header::HeaderName::from_static(REQUEST_ID_HEADER),
+                request_id,
+            );
+        }
+        tracing::info!(status = response.status().as_u16(), "finished");
+        Ok(response)
+    }
+    .instrument(span)
+    .await
+}
+
+// Always fine while the server is up at all
+fn health() -> Response<Body> {
+    Response::new(Body::from(json!({ "status": "ok" }).to_string()))
+}
+
+// Checks that the database could be written, by writing a file next to it
+async fn ready(database_path: &Path) -> Response<Body> {
+    let dirname = database_path
+        .parent()
+        .expect("Expected path to be absolute");
+    let probe_path = dirname.join(".ready");
+    let written = async {
+        tokio::fs::create_dir_all(dirname).await?;
+        tokio::fs::write(&probe_path, b"").await?;
+        tokio::fs::remove_file(&probe_path).await
+    };
+    let (status, body) = match written.await {
+        Ok(()) => (StatusCode::OK, json!({ "status": "ok" })),
+        Err(error) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            json!({
+                "status": "unavailable",
+                "description": format!("Database directory is not writable: {}", error),
+            }),
+        ),
+    };
+    let mut response = Response::new(Body::from(body.to_string()));
+    *response.status_mut() = status;
+    response
+}
+
+// The database path is passed alongside the state, so the readiness check doesn't need the lock
+async fn handle_request(
+    mut request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
+    database_path: Arc<PathBuf>,
+) -> Result<Response<Body>, hyper::Error> {
+    // Probes never wait on the state lock, and don't count as activity for the idle timeout
+    match (request.method(), request.uri().path()) {
+        (&Method::GET, "/health") => return Ok(health()),
+        (&Method::GET, "/ready") => return Ok(ready(&database_path).await),
+        _ => {}
+    }
+    let max_body_bytes = {
+        let app = read_state(&app_state);
+        *app.last_request.lock().unwrap() = Instant::now();
+        app.config.max_body_bytes
+    };
+    request.extensions_mut().insert(BodyLimit(max_body_bytes));
+    let wants_msgpack = header_contains(&request, header::ACCEPT, MSGPACK_MIME);
+    let started = Instant::now();
+    let mut response = route(request, app_state)
+        .instrument(tracing::debug_span!("handler"))
+        .await?;
+    let elapsed_ms = started.elapsed().as_secs_f64() * 1000.0;
+    let server_timing = format!("handler;dur={:.3}", elapsed_ms);
+    if let Ok(server_timing) = header::HeaderValue::from_str(&server_timing) {
+        response.headers_mut().insert(
+            header::HeaderName::from_static("server-timing"),
+            server_timing,
+        );
+    }
+    let is_event_stream = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type == EVENT_STREAM_MIME);
+    // An event stream never ends, so it can't be transcoded as a whole
+    if wants_msgpack && !is_event_stream {
+        encode_msgpack(response).await
+    } else {
+        Ok(response)
+    }
+}
+
+// Every POST endpoint changes the database, apart from these
+const READ_ONLY_POSTS: [&str; 3] = ["/export/projects", "/tasks/query", "/admin/snapshot"];
+
+const SHARE_TOKEN_HEADER: &str = "x-share-token";
+const SHARE_TOKEN_PARAM: &str = "share_token";
+
+// Read endpoints which a share token gives access to. All of them take a project_id.
+const SHARED_ENDPOINTS: [&str; 12] = [
+    "/project",
+    "/project/full",
+    "/project/health",
+    "/project/report",
+    "/project/export/csv",
+    "/project/group_by",
+    "/project/tag_coverage",
+    "/project/bottlenecks",
+    "/task",
+    "/task/state",
+    "/task/comment/pinned",
+    "/tasks/by-tag",
+];
+
+// Looks for a share token in the header, and then in the query string
+fn share_token(request: &Request<Body>) -> Option<String> {
+    if let Some(token) = request.headers().get(SHARE_TOKEN_HEADER) {
+        return token.to_str().ok().map(str::to_owned);
+    }
+    request.uri().query()?.split('&').find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        (name == SHARE_TOKEN_PARAM).then(|| value.to_owned())
+    })
+}
+
 #[derive(Deserialize, Debug)]
-struct PostTaskDescriptionRequest {
+struct SharedRequest {
     project_id: usize,
-    task_id: usize,
-    description: String,
 }
 
-async fn post_task_description(
+// Lets a request carrying a share token through only if it reads the shared project. Unless the
+// request gives its fields as query parameters, the body has to be read to find which project that
+// is, so the request is handed back with the body already decompressed.
+async fn check_share_token(
+    request: Request<Body>,
+    token: &str,
+    app_state: &Arc<RwLock<AppState>>,
+) -> Result<Request<Body>, Box<dyn std::error::Error>> {
+    if request.method() != Method::GET || !SHARED_ENDPOINTS.contains(&request.uri().path()) {
+        return Err(ApiError::Forbidden(
+            "Share tokens only give access to reading a project".to_owned(),
+        )
+        .into());
+    }
+    let shared_project_id = {
+        let app = read_state(app_state);
+        let now = chrono::Utc::now();
+        app.database
+            .share_tokens
+            .get(token)
+            .filter(|share_token| !share_token.is_expired(now))
+            .map(|share_token| share_token.project_id)
+            .ok_or_else(|| ApiError::Forbidden("Invalid or expired share token".to_owned()))?
+    };
+    let (request, shared_request) = match query_params(&request) {
+        Some(query) => {
+            let shared_request = decode_query::<SharedRequest>(query)?;
+            (request, shared_request)
+        }
+        None => {
+            let (request, full_body, is_msgpack) = buffer_body(request).await?;
+            (
+                request,
+                decode_body::<SharedRequest>(&full_body, is_msgpack)?,
+            )
+        }
+    };
+    if shared_request.project_id != shared_project_id {
+        return Err(ApiError::Forbidden(
+            "Share token does not give access to this project".to_owned(),
+        )
+        .into());
+    }
+    Ok(request)
+}
+
+// Reads the body before the handler gets to it. Hands back the body, already decompressed, along
+// with a request which still carries it for the handler to read.
+async fn buffer_body(
     request: Request<Body>,
-    app_state: Arc<Mutex<AppState>>,
-) -> Result<Response<Body>, Box<dyn std::error::Error>> {
-    let full_body = hyper::body::to_bytes(request.into_body()).await?;
-    let request = serde_json::from_slice::<PostTaskDescriptionRequest>(&full_body)?;
-    let mut app = app_state.lock().unwrap();
-    let project = app.database.find_project_by_id_mut(request.project_id)?;
-    let task = project.find_task_by_id_mut(request.task_id)?;
-    task.description = request.description;
-    app.flush()?;
-    Ok(Response::new(Body::from(
-        json!({"status": 200, "description": "OK"}).to_string(),
-    )))
+) -> Result<(Request<Body>, hyper::body::Bytes, bool), Box<dyn std::error::Error>> {
+    let (mut parts, body) = request.into_parts();
+    let mut body_request = Request::new(body);
+    *body_request.headers_mut() = parts.headers.clone();
+    if let Some(limit) = parts.extensions.get::<BodyLimit>() {
+        body_request.extensions_mut().insert(*limit);
+    }
+    let (full_body, is_msgpack) = read_body(body_request).await?;
+    let full_body = hyper::body::Bytes::from(full_body);
+    parts.headers.remove(header::CONTENT_ENCODING);
+    let request = Request::from_parts(parts, Body::from(full_body.clone()));
+    Ok((request, full_body, is_msgpack))
 }
 
-#[derive(Deserialize, Debug)]
-enum DependencyAction {
-    Add,
-    Remove,
+// Told to GET /events subscribers after each successful change to the database
+#[derive(Clone, Serialize, Debug)]
+struct ChangeEvent {
+    // The endpoint which made the change, without the leading slash, e.g. "task/state". Changes
+    // made by the server itself get a name of their own, like "auto_archive".
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    project_id: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    task_id: Option<usize>,
 }
 
-#[derive(Deserialize, Debug)]
-struct PostTaskDependencyRequest {
-    project_id: usize,
-    task_id: usize,
-    dependency: usize,
-    action: DependencyAction,
+// What a request or response says about where a change was made
+#[derive(Default, Deserialize, Debug)]
+struct ChangedIds {
+    project_id: Option<usize>,
+    task_id: Option<usize>,
 }
 
-async fn post_task_dependency(
-    request: Request<Body>,
-    app_state: Arc<Mutex<AppState>>,
+// Events which a slow subscriber may fall behind by before it starts missing them
+const CHANGE_EVENT_CAPACITY: usize = 256;
+
+// Sent when nothing else has been, so proxies don't time the stream out, and so a client which
+// went away is noticed and its stream dropped
+const EVENT_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+const EVENT_STREAM_MIME: &str = "text/event-stream";
+
+async fn events(
+    _request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
 ) -> Result<Response<Body>, Box<dyn std::error::Error>> {
-    let full_body = hyper::body::to_bytes(request.into_body()).await?;
-    let request = serde_json::from_slice::<PostTaskDependencyRequest>(&full_body)?;
-    let mut app = app_state.lock().unwrap();
-    let project = app.database.find_project_by_id_mut(request.project_id)?;
-    let task = project.find_task_by_id_mut(request.task_id)?;
-    match request.action {
-        DependencyAction::Add => task.dependencies.insert(request.dependency),
-        DependencyAction::Remove => task.dependencies.remove(&request.dependency),
-    };
-    app.flush()?;
-    Ok(Response::new(Body::from(
-        json!({"status": 200, "description": "OK"}).to_string(),
-    )))
+    use tokio::sync::broadcast::error::RecvError;
+    let receiver = read_state(&app_state)
+        .change_events
+        .as_ref()
+        .map(|change_events| change_events.subscribe());
+    let keep_alive = tokio::time::interval(EVENT_KEEP_ALIVE);
+    let stream = futures::stream::unfold(
+        (receiver, keep_alive),
+        |(mut receiver, mut keep_alive)| async move {
+            // Nothing to subscribe to once the server is shutting down
+            let subscription = receiver.as_mut()?;
+            let chunk = tokio::select! {
+                event = subscription.recv() => match event {
+                    Ok(event) => format!(
+                        "event: change\ndata: {}\n\n",
+                        serde_json::to_string(&event).ok()?
+                    ),
+                    // Clients should fetch everything again, since they can't tell what they missed
+                    Err(RecvError::Lagged(missed)) => {
+                        format!("event: lagged\ndata: {{\"missed\":{}}}\n\n", missed)
+                    }
+                    Err(RecvError::Closed) => return None,
+                },
+                _ = keep_alive.tick() => ":\n\n".to_owned(),
+            };
+            Some((Ok::<_, std::io::Error>(chunk), (receiver, keep_alive)))
+        },
+    );
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, EVENT_STREAM_MIME)
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(Body::wrap_stream(stream))?)
 }
 
-fn wrap_error(
-    inner: Result<Response<Body>, Box<dyn std::error::Error>>,
+async fn route(
+    request: Request<Body>,
+    app_state: Arc<RwLock<AppState>>,
 ) -> Result<Response<Body>, hyper::Error> {
-    match inner {
-        Ok(response) => Ok(response),
-        Err(error) => {
-            let response_body = json!({
-                "status": 500,
-                "description": error.to_string(),
-            })
-            .to_string();
-            Ok(Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Body::from(response_body))
-                .expect("Failed to build request"))
+    let request = match share_token(&request) {
+        Some(token) => match check_share_token(request, &token, &app_state).await {
+            Ok(request) => request,
+            Err(error) => return wrap_error(Err(error)),
+        },
+        None => request,
+    };
+    if request.method() != Method::POST || READ_ONLY_POSTS.contains(&request.uri().path()) {
+        return dispatch(request, app_state).await;
+    }
+    if let Err(error) = ensure_writable(&app_state).await {
+        return wrap_error(Err(error.into()));
+    }
+    // The body is read here too, to find which project and task the change is about
+    let kind = request.uri().path().trim_start_matches('/').to_owned();
+    let (request, full_body, is_msgpack) = match buffer_body(request).await {
+        Ok(buffered) => buffered,
+        Err(error) => return wrap_error(Err(error)),
+    };
+    let mut changed = decode_body::<ChangedIds>(&full_body, is_msgpack).unwrap_or_default();
+    let revision = read_state(&app_state).database.revision;
+    let mut response = dispatch(request, app_state.clone()).await?;
+    if let Err(error) = flush_if_due(&app_state).await {
+        return wrap_error(Err(error));
+    }
+    // Requests which turned out to change nothing, like deleting a task which was already gone,
+    // aren't announced
+    if !response.status().is_success() || read_state(&app_state).database.revision == revision {
+        return Ok(response);
+    }
+    // Responses about something new say what its IDs turned out to be
+    if response.status() == StatusCode::CREATED {
+        let (parts, body) = response.into_parts();
+        let full_body = hyper::body::to_bytes(body).await?;
+        if let Ok(created) = serde_json::from_slice::<ChangedIds>(&full_body) {
+            changed.project_id = created.project_id.or(changed.project_id);
+            changed.task_id = created.task_id.or(changed.task_id);
         }
+        response = Response::from_parts(parts, Body::from(full_body));
     }
+    read_state(&app_state).publish_change(ChangeEvent {
+        kind,
+        project_id: changed.project_id,
+        task_id: changed.task_id,
+    });
+    Ok(response)
 }
 
-async fn request_handler(
+async fn dispatch(
     request: Request<Body>,
-    app_state: Arc<Mutex<AppState>>,
+    app_state: Arc<RwLock<AppState>>,
 ) -> Result<Response<Body>, hyper::Error> {
     match (request.method(), request.uri().path()) {
+        (&Method::GET, "/events") => wrap_error(events(request, app_state).await),
         (&Method::GET, "/") => wrap_error(list_projects(request, app_state).await),
+        (&Method::GET, "/revision") => wrap_error(revision(request, app_state).await),
+        (&Method::GET, "/metrics") => wrap_error(metrics_endpoint(request, app_state).await),
+        (&Method::GET, "/calendar.ics") => wrap_error(calendar(request, app_state).await),
         (&Method::GET, "/project") => wrap_error(project_details(request, app_state).await),
+        (&Method::GET, "/project/full") => wrap_error(project_full(request, app_state).await),
+        (&Method::GET, "/project/health") => wrap_error(project_health(request, app_state).await),
+        (&Method::GET, "/project/report") => wrap_error(project_report(request, app_state).await),
+        (&Method::GET, "/project/export/csv") => {
+            wrap_error(project_export_csv(request, app_state).await)
+        }
+        (&Method::GET, "/project/group_by") => {
+            wrap_error(project_group_by(request, app_state).await)
+        }
+        (&Method::GET, "/project/tag_coverage") => {
+            wrap_error(project_tag_coverage(request, app_state).await)
+        }
+        (&Method::GET, "/project/schedule") => {
+            wrap_error(project_schedule(request, app_state).await)
+        }
+        (&Method::GET, "/project/dag_info") => {
+            wrap_error(project_dag_info(request, app_state).await)
+        }
+        (&Method::GET, "/project/bottlenecks") => {
+            wrap_error(project_bottlenecks(request, app_state).await)
+        }
         (&Method::GET, "/task") => wrap_error(task_details(request, app_state).await),
+        (&Method::GET, "/project/rebalance") => {
+            wrap_error(project_rebalance(request, app_state).await)
+        }
+        (&Method::GET, "/task/subtree_effort") => {
+            wrap_error(task_subtree_effort(request, app_state).await)
+        }
+        (&Method::GET, "/task/dependents") => wrap_error(task_dependents(request, app_state).await),
+        (&Method::GET, "/task/blocks") => wrap_error(task_blocks(request, app_state).await),
+        (&Method::GET, "/task/state") => wrap_error(task_state(request, app_state).await),
+        (&Method::GET, "/task/global_dependents") => {
+            wrap_error(task_global_dependents(request, app_state).await)
+        }
+        (&Method::GET, "/task/comment/pinned") => {
+            wrap_error(task_pinned_comments(request, app_state).await)
+        }
+        (&Method::GET, "/assignee/projects") => {
+            wrap_error(assignee_projects(request, app_state).await)
+        }
+        (&Method::GET, "/admin/flush_status") => {
+            wrap_error(admin_flush_status(request, app_state).await)
+        }
+        (&Method::GET, "/admin/snapshots") => wrap_error(admin_snapshots(request, app_state).await),
+        (&Method::POST, "/admin/snapshot") => {
+            wrap_error(post_admin_snapshot(request, app_state).await)
+        }
+        (&Method::POST, "/admin/restore") => {
+            wrap_error(post_admin_restore(request, app_state).await)
+        }
+        (&Method::GET, "/admin/history") => wrap_error(admin_history(request, app_state).await),
+        (&Method::GET, "/tasks/by-tag") => wrap_error(tasks_by_tag(request, app_state).await),
+        (&Method::GET, "/tasks/overdue") => wrap_error(tasks_overdue(request, app_state).await),
+        (&Method::GET, "/search") => wrap_error(search(request, app_state).await),
+        (&Method::GET, "/tags/tasks") => wrap_error(tags_tasks(request, app_state).await),
+        (&Method::GET, "/tags/similar") => wrap_error(tags_similar(request, app_state).await),
+        (&Method::POST, "/tags/merge") => wrap_error(post_tags_merge(request, app_state).await),
+        (&Method::GET, "/report/completions_by_day") => {
+            wrap_error(report_completions_by_day(request, app_state).await)
+        }
+        (&Method::GET, "/report/load") => wrap_error(report_load(request, app_state).await),
+        (&Method::GET, "/report/estimation_accuracy") => {
+            wrap_error(report_estimation_accuracy(request, app_state).await)
+        }
         (&Method::POST, "/project/create") => {
             wrap_error(post_project_create(request, app_state).await)
         }
+        (&Method::POST, "/project/duplicate") => {
+            wrap_error(post_project_duplicate(request, app_state).await)
+        }
         (&Method::POST, "/project/delete") => {
             wrap_error(post_project_delete(request, app_state).await)
         }
         (&Method::POST, "/project/name") => wrap_error(post_project_name(request, app_state).await),
+        (&Method::POST, "/project/share") => {
+            wrap_error(post_project_share(request, app_state).await)
+        }
+        (&Method::POST, "/project/share/revoke") => {
+            wrap_error(post_project_share_revoke(request, app_state).await)
+        }
+        (&Method::POST, "/project/group") => {
+            wrap_error(post_project_group(request, app_state).await)
+        }
+        (&Method::POST, "/project/shift_due_dates") => {
+            wrap_error(post_project_shift_due_dates(request, app_state).await)
+        }
         (&Method::POST, "/project/description") => {
             wrap_error(post_project_description(request, app_state).await)
         }
+        (&Method::GET, "/query/run") => wrap_error(query_run(request, app_state).await),
+        (&Method::GET, "/query/list") => wrap_error(query_list(request, app_state).await),
+        (&Method::POST, "/query/save") => wrap_error(post_query_save(request, app_state).await),
+        (&Method::POST, "/query/delete") => wrap_error(post_query_delete(request, app_state).await),
+        (&Method::POST, "/tasks/query") => wrap_error(post_tasks_query(request, app_state).await),
+        (&Method::POST, "/import") => wrap_error(post_import(request, app_state).await),
+        (&Method::GET, "/webhooks") => wrap_error(list_webhooks(request, app_state).await),
+        (&Method::POST, "/webhooks") => wrap_error(post_webhooks(request, app_state).await),
+        (&Method::POST, "/webhooks/delete") => {
+            wrap_error(post_webhooks_delete(request, app_state).await)
+        }
+        (&Method::POST, "/project/import") => {
+            wrap_error(post_project_import(request, app_state).await)
+        }
+        (&Method::POST, "/import/generic") => {
+            wrap_error(post_import_generic(request, app_state).await)
+        }
+        (&Method::POST, "/export/projects") => {
+            wrap_error(post_export_projects(request, app_state).await)
+        }
+        (&Method::POST, "/admin/recompute_ids") => {
+            wrap_error(post_admin_recompute_ids(request, app_state).await)
+        }
         (&Method::POST, "/task/create") => wrap_error(post_task_create(request, app_state).await),
         (&Method::POST, "/task/delete") => wrap_error(post_task_delete(request, app_state).await),
+        (&Method::POST, "/task/move") => wrap_error(post_task_move(request, app_state).await),
+        (&Method::POST, "/task/duplicate") => {
+            wrap_error(post_task_duplicate(request, app_state).await)
+        }
         (&Method::POST, "/task/title") => wrap_error(post_task_title(request, app_state).await),
         (&Method::POST, "/task/description") => {
             wrap_error(post_task_description(request, app_state).await)
@@ -567,47 +6138,1288 @@ async fn request_handler(
         (&Method::POST, "/task/dependency") => {
             wrap_error(post_task_dependency(request, app_state).await)
         }
+        (&Method::POST, "/task/chain") => wrap_error(post_task_chain(request, app_state).await),
+        (&Method::POST, "/task/relation/add") => {
+            wrap_error(post_task_relation_add(request, app_state).await)
+        }
+        (&Method::POST, "/task/relation/remove") => {
+            wrap_error(post_task_relation_remove(request, app_state).await)
+        }
+        (&Method::POST, "/task/attachment/add") => {
+            wrap_error(post_task_attachment_add(request, app_state).await)
+        }
+        (&Method::POST, "/task/attachment/remove") => {
+            wrap_error(post_task_attachment_remove(request, app_state).await)
+        }
         (&Method::POST, "/task/state") => wrap_error(post_task_state(request, app_state).await),
+        (&Method::POST, "/task/state/bulk") => {
+            wrap_error(post_task_state_bulk(request, app_state).await)
+        }
+        (&Method::POST, "/task/priority") => {
+            wrap_error(post_task_priority(request, app_state).await)
+        }
+        (&Method::POST, "/task/due") => wrap_error(post_task_due(request, app_state).await),
+        (&Method::POST, "/task/tag") => wrap_error(post_task_tag(request, app_state).await),
+        (&Method::POST, "/task/reparent_bulk") => {
+            wrap_error(post_task_reparent_bulk(request, app_state).await)
+        }
+        (&Method::POST, "/task/checklist/add") => {
+            wrap_error(post_task_checklist_add(request, app_state).await)
+        }
+        (&Method::POST, "/task/checklist/promote") => {
+            wrap_error(post_task_checklist_promote(request, app_state).await)
+        }
         (&Method::POST, "/task/comment") => wrap_error(post_task_comment(request, app_state).await),
+        (&Method::POST, "/task/comment/pin") => {
+            wrap_error(post_task_comment_pin(request, app_state).await)
+        }
+        (&Method::POST, "/task/assignee") => {
+            wrap_error(post_task_assignee(request, app_state).await)
+        }
+        (&Method::POST, "/task/estimate") => {
+            wrap_error(post_task_estimate(request, app_state).await)
+        }
+        (&Method::POST, "/task/archive") => wrap_error(post_task_archive(request, app_state).await),
+        (&Method::POST, "/task/comment/edit") => {
+            wrap_error(post_task_comment_edit(request, app_state).await)
+        }
+        (&Method::POST, "/task/comment/delete") => {
+            wrap_error(post_task_comment_delete(request, app_state).await)
+        }
+        (&Method::POST, "/task/color") => wrap_error(post_task_color(request, app_state).await),
+        (&Method::POST, "/task/actual") => wrap_error(post_task_actual(request, app_state).await),
         _ => {
             let mut response = Response::new(Body::empty());
             *response.status_mut() = StatusCode::NOT_FOUND;
+            response.extensions_mut().insert(UnknownRoute);
             Ok(response)
         }
     }
 }
 
-// Parses arguments and return port to listen on
-fn parse_args() -> u16 {
+fn exit_with_usage(program: &str) -> ! {
+    eprintln!(
+        "ERROR: Usage {} PORT [--max-log-length ENTRIES] [--idle-timeout SECONDS] \
+         [--flush-threshold CHANGES] [--flush-cooldown MILLISECONDS] \
+         [--flush-interval MILLISECONDS] [--flush-failure-limit FAILURES] [--alert-webhook URL] \
+         [--terminal-states STATES] [--auto-archive-days DAYS] [--database PATH] [--host ADDRESS] \
+         [--cors-origin ORIGIN] [--max-body-bytes BYTES]",
+        program
+    );
+    std::process::exit(1);
+}
+
+// Parses arguments into the server configuration
+fn parse_args() -> Config {
     let args = std::env::args().collect::<Vec<_>>();
-    if args.len() != 2 {
-        eprintln!("ERROR: Usage {} PORT", args[0]);
-        std::process::exit(1);
+    if args.len() < 2 {
+        exit_with_usage(&args[0]);
+    }
+    let mut config = Config::new(args[1].parse().expect("Could not parse port"));
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        let mut value = || options.next().unwrap_or_else(|| exit_with_usage(&args[0]));
+        match option.as_str() {
+            "--max-log-length" => {
+                config.max_log_length =
+                    Some(value().parse().expect("Could not parse maximum log length"))
+            }
+            "--max-body-bytes" => {
+                config.max_body_bytes = value().parse().expect("Could not parse maximum body size");
+                if config.max_body_bytes == 0 {
+                    eprintln!("ERROR: Maximum body size must be at least 1");
+                    std::process::exit(1);
+                }
+            }
+            "--idle-timeout" => {
+                config.idle_timeout = Some(Duration::from_secs(
+                    value().parse().expect("Could not parse idle timeout"),
+                ))
+            }
+            "--flush-threshold" => {
+                let threshold = value().parse().expect("Could not parse flush threshold");
+                config.flush_threshold = Some(threshold);
+                if threshold == 0 {
+                    eprintln!("ERROR: Flush threshold must be at least 1");
+                    std::process::exit(1);
+                }
+            }
+            "--flush-cooldown" => {
+                config.flush_cooldown = Some(Duration::from_millis(
+                    value().parse().expect("Could not parse flush cooldown"),
+                ))
+            }
+            "--flush-interval" => {
+                config.flush_interval = Some(Duration::from_millis(
+                    value().parse().expect("Could not parse flush interval"),
+                ))
+            }
+            "--terminal-states" => {
+                config.terminal_states = value()
+                    .split(',')
+                    .map(|name| {
+                        serde_json::from_value(json!(name.trim())).unwrap_or_else(|_| {
+                            eprintln!("ERROR: Unknown state: {}", name);
+                            std::process::exit(1);
+                        })
+                    })
+                    .collect()
+            }
+            "--database" => config.database_path = Some(PathBuf::from(value())),
+            "--cors-origin" => {
+                let origin = value();
+                config.cors_origin = header::HeaderValue::from_str(origin).unwrap_or_else(|_| {
+                    eprintln!("ERROR: Invalid CORS origin `{}`", origin);
+                    std::process::exit(1);
+                })
+            }
+            "--host" => {
+                let host = value();
+                config.host = host.parse().unwrap_or_else(|_| {
+                    eprintln!(
+                        "ERROR: Invalid host `{}`, expected an IP address like 0.0.0.0 or ::1",
+                        host
+                    );
+                    std::process::exit(1);
+                })
+            }
+            "--auto-archive-days" => {
+                let days: u64 = value().parse().expect("Could not parse auto-archive age");
+                config.auto_archive_after = Some(Duration::from_secs(days * 24 * 60 * 60));
+            }
+            "--flush-failure-limit" => {
+                let limit = value()
+                    .parse()
+                    .expect("Could not parse flush failure limit");
+                if limit == 0 {
+                    eprintln!("ERROR: Flush failure limit must be at least 1");
+                    std::process::exit(1);
+                }
+                config.flush_failure_limit = Some(limit);
+            }
+            "--alert-webhook" => {
+                config.flush_failure_action = FlushFailureAction::Alert(
+                    value().parse().expect("Could not parse alert webhook URL"),
+                )
+            }
+            _ => exit_with_usage(&args[0]),
+        }
+    }
+    config
+}
+
+const AUTO_ARCHIVE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Runs for as long as the server does, archiving long-finished tasks every AUTO_ARCHIVE_INTERVAL,
+// starting straight away
+async fn auto_archive(app_state: Arc<RwLock<AppState>>, age: Duration) {
+    let age = chrono::Duration::from_std(age).expect("Auto-archive age is too large");
+    let mut interval = tokio::time::interval(AUTO_ARCHIVE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let archived = {
+            let mut app = write_state(&app_state);
+            let archived = app.archive_finished_tasks(age);
+            if archived > 0 {
+                app.record_mutation();
+            }
+            archived
+        };
+        if archived == 0 {
+            continue;
+        }
+        eprintln!("* Automatically archived {} finished tasks", archived);
+        // Errors are already reported by finish_flush
+        if flush_if_due(&app_state).await.is_ok() {
+            read_state(&app_state).publish_change(ChangeEvent {
+                kind: "auto_archive".to_owned(),
+                project_id: None,
+                task_id: None,
+            });
+        }
+    }
+}
+
+// Runs for as long as the server does, carrying out flushes which were held back by the cooldown or
+// left to the flush interval. A flush which fails is retried once the gap has passed again.
+async fn flush_deferred_changes(app_state: Arc<RwLock<AppState>>) {
+    let flush_waker = read_state(&app_state).flush_waker.clone();
+    loop {
+        let due = read_state(&app_state).deferred_flush_due();
+        match due {
+            Some(due) => tokio::time::sleep_until(due.into()).await,
+            None => {
+                flush_waker.notified().await;
+                continue;
+            }
+        }
+        let due = read_state(&app_state)
+            .deferred_flush_due()
+            .is_some_and(|due| due <= Instant::now());
+        if due {
+            // Errors are already reported by finish_flush
+            let _ = flush_database(&app_state).await;
+        }
+    }
+}
+
+// Resolves once no request has come in for the given duration
+async fn idle_for(app_state: Arc<RwLock<AppState>>, idle_timeout: Duration) {
+    loop {
+        let idle = read_state(&app_state)
+            .last_request
+            .lock()
+            .unwrap()
+            .elapsed();
+        if idle >= idle_timeout {
+            return;
+        }
+        tokio::time::sleep(idle_timeout - idle).await;
+    }
+}
+
+async fn shutdown_signal(app_state: Arc<RwLock<AppState>>, idle_timeout: Option<Duration>) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Could not set up Ctrl+C signal handler")
+    };
+    match idle_timeout {
+        Some(idle_timeout) => tokio::select! {
+            _ = ctrl_c => {}
+            _ = idle_for(app_state.clone(), idle_timeout) => {
+                eprintln!("* Shutting down after {} seconds idle", idle_timeout.as_secs());
+            }
+        },
+        None => ctrl_c.await,
     }
-    args[1].parse().expect("Could not parse port")
+    // Graceful shutdown waits for every connection to finish, which open event streams never do
+    write_state(&app_state).change_events = None;
 }
 
 #[tokio::main]
 async fn main() {
-    let port = parse_args();
-    let app_state = Arc::new(Mutex::new(AppState::initialize()));
-    let addr = SocketAddr::from(([127, 0, 0, 1], port));
-    let server = Server::bind(&addr)
+    // Silent apart from errors, unless RUST_LOG asks for more, e.g. RUST_LOG=btasks=debug
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+        .with_writer(std::io::stderr)
+        .init();
+    let config = parse_args();
+    let addr = SocketAddr::new(config.host, config.port);
+    let idle_timeout = config.idle_timeout;
+    let background_flush = config.flush_cooldown.is_some() || config.flush_interval.is_some();
+    let auto_archive_after = config.auto_archive_after;
+    let app_state = Arc::new(RwLock::new(AppState::initialize(config).await));
+    if background_flush {
+        tokio::spawn(flush_deferred_changes(app_state.clone()));
+    }
+    if let Some(auto_archive_after) = auto_archive_after {
+        tokio::spawn(auto_archive(app_state.clone(), auto_archive_after));
+    }
+    let service_state = app_state.clone();
+    let (database_path, cors_origin) = {
+        let app = read_state(&app_state);
+        (
+            Arc::new(app.database_path.clone()),
+            app.config.cors_origin.clone(),
+        )
+    };
+    let server = Server::try_bind(&addr)
+        .unwrap_or_else(|error| {
+            eprintln!("ERROR: Could not listen on {}: {}", addr, error);
+            std::process::exit(1);
+        })
         .serve(make_service_fn(move |_conn| {
-            let app_state = app_state.clone();
+            let app_state = service_state.clone();
+            let database_path = database_path.clone();
+            let cors_origin = cors_origin.clone();
             async move {
                 Ok::<_, hyper::Error>(service_fn(move |request| {
-                    request_handler(request, app_state.clone())
+                    request_handler(
+                        request,
+                        app_state.clone(),
+                        database_path.clone(),
+                        cors_origin.clone(),
+                    )
                 }))
             }
         }))
-        .with_graceful_shutdown(async {
-            tokio::signal::ctrl_c()
-                .await
-                .expect("Could not set up Ctrl+C signal handler")
-        });
-    eprintln!("* Listening on port {}", port);
+        .with_graceful_shutdown(shutdown_signal(app_state.clone(), idle_timeout));
+    eprintln!("* Listening on {}", addr);
     if let Err(e) = server.await {
         eprintln!("server error: {}", e);
     }
+    // Always written, so changes still waiting on the cooldown or the flush interval aren't lost
+    if let Err(e) = flush_database(&app_state).await {
+        eprintln!("Could not flush database: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A server state with a database file of its own, so tests can run in parallel. The file is
+    // removed along with its directory when the server is dropped.
+    struct TestServer {
+        app_state: Arc<RwLock<AppState>>,
+        directory: PathBuf,
+    }
+
+    impl TestServer {
+        async fn new(name: &str) -> TestServer {
+            TestServer::with_config(name, |_| {}).await
+        }
+
+        async fn with_config(name: &str, configure: impl FnOnce(&mut Config)) -> TestServer {
+            let directory =
+                std::env::temp_dir().join(format!("btasks-test-{}-{}", std::process::id(), name));
+            let _ = std::fs::remove_dir_all(&directory);
+            let mut config = Config::new(0);
+            config.database_path = Some(directory.join("database.json"));
+            configure(&mut config);
+            TestServer {
+                app_state: Arc::new(RwLock::new(AppState::initialize(config).await)),
+                directory,
+            }
+        }
+
+        async fn send(&self, request: Request<Body>) -> (StatusCode, serde_json::Value) {
+            let database_path = Arc::new(read_state(&self.app_state).database_path.clone());
+            let response = handle_request(request, self.app_state.clone(), database_path)
+                .await
+                .unwrap();
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            (status, serde_json::from_slice(&body).unwrap())
+        }
+
+        async fn call(
+            &self,
+            method: Method,
+            path: &str,
+            body: serde_json::Value,
+        ) -> (StatusCode, serde_json::Value) {
+            let request = Request::builder()
+                .method(method)
+                .uri(path)
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            self.send(request).await
+        }
+
+        async fn post(
+            &self,
+            path: &str,
+            body: serde_json::Value,
+        ) -> (StatusCode, serde_json::Value) {
+            self.call(Method::POST, path, body).await
+        }
+
+        async fn create_project(&self) -> usize {
+            let (status, body) = self
+                .post(
+                    "/project/create",
+                    json!({"name": "Project", "description": ""}),
+                )
+                .await;
+            assert_eq!(status, StatusCode::CREATED);
+            body["project_id"].as_u64().unwrap() as usize
+        }
+
+        async fn create_task(&self, project_id: usize, title: &str) -> usize {
+            let (status, body) = self
+                .post(
+                    "/task/create",
+                    json!({"project_id": project_id, "title": title, "description": ""}),
+                )
+                .await;
+            assert_eq!(status, StatusCode::CREATED);
+            body["task_id"].as_u64().unwrap() as usize
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.directory);
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_fields_are_named() {
+        let server = TestServer::new("missing_fields_are_named").await;
+        let project_id = server.create_project().await;
+        let full = json!({"project_id": project_id, "title": "Task", "description": ""});
+        for field in ["project_id", "title", "description"] {
+            let mut body = full.clone();
+            body.as_object_mut().unwrap().remove(field);
+            let (status, response) = server.post("/task/create", body).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "without {}", field);
+            assert_eq!(response["code"], "MISSING_FIELD", "without {}", field);
+            let description = response["description"].as_str().unwrap();
+            assert!(
+                description.contains(&format!("`{}`", field)),
+                "{} doesn't name {}",
+                description,
+                field
+            );
+        }
+        let (status, _) = server.post("/task/create", full).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    // Sets up a database file with one project in it, as written by the server. Returns its path
+    // and contents.
+    async fn written_database(server: &TestServer) -> (PathBuf, Vec<u8>) {
+        let project_id = server.create_project().await;
+        assert_eq!(project_id, 0);
+        let path = read_state(&server.app_state).database_path.clone();
+        let contents = std::fs::read(&path).unwrap();
+        (path, contents)
+    }
+
+    #[tokio::test]
+    async fn truncated_temp_file_leaves_database_whole() {
+        let server = TestServer::new("truncated_temp_file_leaves_database_whole").await;
+        let (path, contents) = written_database(&server).await;
+        // What a flush killed part way through writing leaves behind
+        let temp_path = AppState::get_temp_path(&path);
+        std::fs::write(&temp_path, &contents[..contents.len() / 2]).unwrap();
+        let database = AppState::load_database(&path).await.unwrap();
+        assert!(database.find_project_by_id(0).is_ok());
+    }
+
+    #[tokio::test]
+    async fn complete_temp_file_is_recovered() {
+        let server = TestServer::new("complete_temp_file_is_recovered").await;
+        let (path, contents) = written_database(&server).await;
+        // What a first flush killed before renaming its temporary file leaves behind
+        std::fs::remove_file(&path).unwrap();
+        let temp_path = AppState::get_temp_path(&path);
+        std::fs::write(&temp_path, &contents).unwrap();
+        let database = AppState::load_database(&path).await.unwrap();
+        assert!(database.find_project_by_id(0).is_ok());
+        assert_eq!(std::fs::read(&path).unwrap(), contents);
+        assert!(!temp_path.exists());
+    }
+
+    #[tokio::test]
+    async fn truncated_temp_file_is_not_recovered() {
+        let server = TestServer::new("truncated_temp_file_is_not_recovered").await;
+        let (path, contents) = written_database(&server).await;
+        std::fs::remove_file(&path).unwrap();
+        let temp_path = AppState::get_temp_path(&path);
+        std::fs::write(&temp_path, &contents[..contents.len() / 2]).unwrap();
+        assert!(AppState::load_database(&path).await.is_none());
+        // Left for someone to look at, since it might be all that's left of the database
+        assert!(temp_path.exists());
+    }
+
+    // Checks every lookup through the index against where a linear scan finds the task
+    fn assert_index_matches(project: &Project) {
+        assert_eq!(project.task_positions.len(), project.tasks.len());
+        for (index, task) in project.tasks.iter().enumerate() {
+            assert_eq!(project.task_position(task.id).unwrap(), index);
+            assert_eq!(project.find_task_by_id(task.id).unwrap().id, task.id);
+        }
+    }
+
+    #[test]
+    fn task_index_follows_changes() {
+        let mut database = Database::default();
+        let project_id = database.create_project("Project".to_owned(), String::new());
+        let other_id = database.create_project("Other".to_owned(), String::new());
+        let project = database.find_project_by_id_mut(project_id).unwrap();
+        let ids = (0..5)
+            .map(|i| project.create_task(format!("Task {}", i), String::new()))
+            .collect::<Vec<_>>();
+        assert_index_matches(project);
+        project.remove_task(ids[1]).unwrap();
+        assert_index_matches(project);
+        assert!(project.find_task_by_id(ids[1]).is_err());
+        // Lookups mustn't rely on the tasks being sorted by ID
+        project.tasks.reverse();
+        project.reindex_tasks();
+        assert_index_matches(project);
+        assert_eq!(project.find_task_by_id(ids[4]).unwrap().title, "Task 4");
+        let (task, _) = project.remove_task(ids[3]).unwrap();
+        assert_index_matches(project);
+        assert!(project.find_task_by_id(ids[3]).is_err());
+        let other = database.find_project_by_id_mut(other_id).unwrap();
+        other.create_task("Already there".to_owned(), String::new());
+        let moved_id = other.insert_task(task);
+        assert_index_matches(other);
+        assert_eq!(other.find_task_by_id(moved_id).unwrap().title, "Task 3");
+    }
+
+    #[test]
+    fn external_project_is_reindexed() {
+        let mut database = Database::default();
+        let project_id = database.create_project("Project".to_owned(), String::new());
+        let project = database.find_project_by_id_mut(project_id).unwrap();
+        for i in 0..4 {
+            project.create_task(format!("Task {}", i), String::new());
+        }
+        project.tasks.swap(0, 3);
+        project.tasks.swap(1, 2);
+        assert!(project.validate_external().is_empty());
+        assert_index_matches(project);
+        assert_eq!(project.find_task_by_id(2).unwrap().title, "Task 2");
+    }
+
+    #[tokio::test]
+    async fn oversized_bodies_are_rejected() {
+        let server = TestServer::with_config("oversized_bodies_are_rejected", |config| {
+            config.max_body_bytes = 256;
+        })
+        .await;
+        let body = json!({"name": "Project", "description": "x".repeat(512)}).to_string();
+        let create = || {
+            Request::builder()
+                .method(Method::POST)
+                .uri("/project/create")
+        };
+        let declared = create()
+            .header(header::CONTENT_LENGTH, body.len())
+            .body(Body::from(body.clone()))
+            .unwrap();
+        // Doesn't say how long it is, so it can only be caught while reading it
+        let chunks = body
+            .as_bytes()
+            .chunks(64)
+            .map(|chunk| Ok::<_, std::io::Error>(chunk.to_vec()))
+            .collect::<Vec<_>>();
+        let streamed = create()
+            .body(Body::wrap_stream(futures::stream::iter(chunks)))
+            .unwrap();
+        // Small on the wire, but over the limit once inflated
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        encoder.write_all(body.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert!(compressed.len() < 256);
+        let gzipped = create()
+            .header(header::CONTENT_ENCODING, "gzip")
+            .body(Body::from(compressed))
+            .unwrap();
+        for request in [declared, streamed, gzipped] {
+            let (status, response) = server.send(request).await;
+            assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+            assert_eq!(response["code"], "BODY_TOO_LARGE");
+        }
+        assert!(read_state(&server.app_state).database.projects.is_empty());
+        let (status, _) = server
+            .post(
+                "/project/create",
+                json!({"name": "Project", "description": ""}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn deleting_twice_succeeds() {
+        let server = TestServer::new("deleting_twice_succeeds").await;
+        let project_id = server.create_project().await;
+        let task_id = server.create_task(project_id, "Task").await;
+        let delete_task = json!({"project_id": project_id, "task_id": task_id});
+        let (status, response) = server.post("/task/delete", delete_task.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response["already_absent"], false);
+        let revision = read_state(&server.app_state).database.revision;
+        let (status, response) = server.post("/task/delete", delete_task).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response["already_absent"], true);
+        // Nothing changed the second time
+        assert_eq!(read_state(&server.app_state).database.revision, revision);
+        let delete_project = json!({ "project_id": project_id });
+        let (status, response) = server.post("/project/delete", delete_project.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response["already_absent"], false);
+        let (status, response) = server.post("/project/delete", delete_project).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response["already_absent"], true);
+        // Retrying a task delete still works once its project is gone too
+        let (status, response) = server
+            .post(
+                "/task/delete",
+                json!({"project_id": project_id, "task_id": task_id}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response["already_absent"], true);
+    }
+
+    async fn add_dependency(
+        server: &TestServer,
+        project_id: usize,
+        task_id: usize,
+        dependency: usize,
+    ) -> (StatusCode, serde_json::Value) {
+        server
+            .post(
+                "/task/dependency",
+                json!({
+                    "project_id": project_id,
+                    "task_id": task_id,
+                    "dependency": dependency,
+                    "action": "Add",
+                }),
+            )
+            .await
+    }
+
+    // Checks that adding the dependency is refused with the given cycle, and changes nothing
+    async fn assert_cycle(
+        server: &TestServer,
+        project_id: usize,
+        task_id: usize,
+        dependency: usize,
+        cycle: &str,
+    ) {
+        let revision = read_state(&server.app_state).database.revision;
+        let (status, response) = add_dependency(server, project_id, task_id, dependency).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(response["code"], "CYCLE_DETECTED");
+        let description = response["description"].as_str().unwrap();
+        assert!(description.ends_with(cycle), "{}", description);
+        let app = read_state(&server.app_state);
+        assert_eq!(app.database.revision, revision);
+        let project = app.database.find_project_by_id(project_id).unwrap();
+        let task = project.find_task_by_id(task_id).unwrap();
+        assert!(!task.dependencies.contains(&dependency));
+    }
+
+    #[tokio::test]
+    async fn dependency_cycles_are_rejected() {
+        let server = TestServer::new("dependency_cycles_are_rejected").await;
+        let project_id = server.create_project().await;
+        let mut ids = Vec::new();
+        for i in 0..4 {
+            ids.push(server.create_task(project_id, &format!("Task {}", i)).await);
+        }
+        assert_cycle(&server, project_id, ids[0], ids[0], "0 -> 0").await;
+        let (status, _) = add_dependency(&server, project_id, ids[1], ids[0]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_cycle(&server, project_id, ids[0], ids[1], "0 -> 1 -> 0").await;
+        for pair in ids.windows(2).skip(1) {
+            let (status, _) = add_dependency(&server, project_id, pair[1], pair[0]).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        assert_cycle(&server, project_id, ids[0], ids[3], "0 -> 3 -> 2 -> 1 -> 0").await;
+    }
+
+    #[tokio::test]
+    async fn deleted_task_leaves_nothing_pointing_at_it() {
+        let server = TestServer::new("deleted_task_leaves_nothing_pointing_at_it").await;
+        let project_id = server.create_project().await;
+        let deleted = server.create_task(project_id, "Deleted").await;
+        let dependent = server.create_task(project_id, "Dependent").await;
+        let child = server.create_task(project_id, "Child").await;
+        let (status, _) = add_dependency(&server, project_id, dependent, deleted).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = server
+            .post(
+                "/task/relation/add",
+                json!({
+                    "project_id": project_id,
+                    "task_id": child,
+                    "kind": "RelatesTo",
+                    "target": deleted,
+                }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = server
+            .post(
+                "/task/reparent_bulk",
+                json!({"project_id": project_id, "task_ids": [child], "new_parent": deleted}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, response) = server
+            .post(
+                "/task/delete",
+                json!({"project_id": project_id, "task_id": deleted}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response["dependency_removed_from"], json!([dependent]));
+        let app = read_state(&server.app_state);
+        let project = app.database.find_project_by_id(project_id).unwrap();
+        assert_eq!(project.tasks.len(), 2);
+        for task in &project.tasks {
+            assert!(!task.dependencies.contains(&deleted));
+            assert!(task
+                .relations
+                .iter()
+                .all(|relation| relation.task_id != deleted));
+            assert_ne!(task.parent, Some(deleted));
+        }
+    }
+
+    #[tokio::test]
+    async fn locations_can_be_followed() {
+        let server = TestServer::new("locations_can_be_followed").await;
+        let project_id = server.create_project().await;
+        let location = project_location(project_id);
+        let request = Request::get(&location).body(Body::empty()).unwrap();
+        let (status, project) = server.send(request).await;
+        assert_eq!(status, StatusCode::OK, "{}", project);
+        assert_eq!(project["id"], project_id);
+        let task_id = server.create_task(project_id, "Task").await;
+        let location = task_location(project_id, task_id);
+        let request = Request::get(&location).body(Body::empty()).unwrap();
+        let (status, task) = server.send(request).await;
+        assert_eq!(status, StatusCode::OK, "{}", task);
+        assert_eq!(task["title"], "Task");
+        let request = Request::get("/task?project_id=zero&task_id=0")
+            .body(Body::empty())
+            .unwrap();
+        let (status, response) = server.send(request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(response["description"]
+            .as_str()
+            .unwrap()
+            .contains("`project_id`"));
+    }
+
+    #[tokio::test]
+    async fn saved_query_runs_by_name_in_query_string() {
+        let server = TestServer::new("saved_query_runs_by_name_in_query_string").await;
+        let project_id = server.create_project().await;
+        for title in ["First", "Second", "Third"] {
+            server.create_task(project_id, title).await;
+        }
+        let (status, _) = server
+            .post("/query/save", json!({"name": "Every task", "query": {}}))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let request = Request::get("/query/run?name=Every+task&offset=1&limit=1")
+            .body(Body::empty())
+            .unwrap();
+        let (status, response) = server.send(request).await;
+        assert_eq!(status, StatusCode::OK, "{}", response);
+        assert_eq!(response["total"], 3);
+        assert_eq!(response["offset"], 1);
+        assert_eq!(response["tasks"].as_array().unwrap().len(), 1);
+        let request = Request::get("/query/run?name=Missing")
+            .body(Body::empty())
+            .unwrap();
+        let (status, response) = server.send(request).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(response["code"], "QUERY_NOT_FOUND");
+    }
+
+    #[tokio::test]
+    async fn project_list_pages_have_their_own_etags() {
+        let server = TestServer::new("project_list_pages_have_their_own_etags").await;
+        for _ in 0..3 {
+            server.create_project().await;
+        }
+        let list = |uri: &str, etag: Option<&str>| {
+            let mut request = Request::get(uri);
+            if let Some(etag) = etag {
+                request = request.header(header::IF_NONE_MATCH, etag);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+        let database_path = Arc::new(read_state(&server.app_state).database_path.clone());
+        let send =
+            |request| handle_request(request, server.app_state.clone(), database_path.clone());
+        let first_page = send(list("/?limit=2", None)).await.unwrap();
+        assert_eq!(first_page.status(), StatusCode::OK);
+        let etag = first_page.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let body = hyper::body::to_bytes(first_page.into_body()).await.unwrap();
+        let body = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(body["projects"].as_array().unwrap().len(), 2);
+        let unchanged = send(list("/?limit=2", Some(&etag))).await.unwrap();
+        assert_eq!(unchanged.status(), StatusCode::NOT_MODIFIED);
+        for other_page in ["/?offset=2&limit=2", "/?limit=3", "/?limit=2&grouped=true"] {
+            let response = send(list(other_page, Some(&etag))).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{}", other_page);
+        }
+        server.create_project().await;
+        let changed = send(list("/?limit=2", Some(&etag))).await.unwrap();
+        assert_eq!(changed.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn health_counts_overdue_and_wip() {
+        let server = TestServer::new("health_counts_overdue_and_wip").await;
+        let project_id = server.create_project().await;
+        for i in 0..5 {
+            let task_id = server.create_task(project_id, &format!("Task {}", i)).await;
+            let (status, _) = server
+                .post(
+                    "/task/state",
+                    json!({"project_id": project_id, "task_id": task_id, "new_state": "InProgress"}),
+                )
+                .await;
+            assert_eq!(status, StatusCode::OK);
+            let (status, _) = server
+                .post(
+                    "/task/assignee",
+                    json!({"project_id": project_id, "task_id": task_id, "assignee": "alice"}),
+                )
+                .await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        let (status, _) = server
+            .post(
+                "/task/due",
+                json!({"project_id": project_id, "task_id": 0, "due_date": 0}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, health) = server
+            .call(
+                Method::GET,
+                "/project/health",
+                json!({ "project_id": project_id }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let affected = |name: &str| {
+            health["factors"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|factor| factor["name"] == name)
+                .unwrap()["affected_tasks"]
+                .clone()
+        };
+        assert_eq!(affected("overdue"), 1);
+        // Two of alice's five tasks in progress are over the limit
+        assert_eq!(affected("wip"), 2);
+        assert_eq!(affected("blocked"), 0);
+        // 30 * 1/5 for the overdue task, and 20 * 2/5 for the tasks over the limit
+        assert_eq!(health["score"], 86);
+    }
+
+    #[tokio::test]
+    async fn merged_tags_are_normalized() {
+        let server = TestServer::new("merged_tags_are_normalized").await;
+        let project_id = server.create_project().await;
+        let misspelt = server.create_task(project_id, "Misspelt").await;
+        let merged = server.create_task(project_id, "Merged").await;
+        let (status, _) = server
+            .post(
+                "/task/tag",
+                json!({"project_id": project_id, "task_id": misspelt, "tag": "bakend", "action": "Add"}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        {
+            // As an older database may have it, from before tags were normalized
+            let mut app = write_state(&server.app_state);
+            let project = app.database.find_project_by_id_mut(project_id).unwrap();
+            let task = project.find_task_by_id_mut(merged).unwrap();
+            task.tags.insert("Backend".to_owned());
+        }
+        let (status, response) = server
+            .post(
+                "/tags/merge",
+                json!({"from": [" BAKEND ", "backend"], "into": " Backend "}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response["affected_tasks"], 2);
+        let app = read_state(&server.app_state);
+        let project = app.database.find_project_by_id(project_id).unwrap();
+        for task in &project.tasks {
+            assert_eq!(task.tags, HashSet::from(["backend".to_owned()]));
+        }
+    }
+
+    #[tokio::test]
+    async fn generic_import_reads_priority_and_tags() {
+        let server = TestServer::new("generic_import_reads_priority_and_tags").await;
+        let project_id = server.create_project().await;
+        let (status, response) = server
+            .post(
+                "/import/generic",
+                json!({"project_id": project_id, "tasks": [
+                    {"title": "Urgent", "priority": "URGENT", "tags": [" Backend ", "", "urgent"]},
+                    {"title": "Unknown", "priority": "whenever"},
+                ]}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let urgent = response["created"][0]["task_id"].as_u64().unwrap() as usize;
+        let unknown = response["created"][1]["task_id"].as_u64().unwrap() as usize;
+        let app = read_state(&server.app_state);
+        let project = app.database.find_project_by_id(project_id).unwrap();
+        let task = project.find_task_by_id(urgent).unwrap();
+        assert_eq!(task.priority, Priority::Critical);
+        assert_eq!(
+            task.tags,
+            HashSet::from(["backend".to_owned(), "urgent".to_owned()])
+        );
+        let task = project.find_task_by_id(unknown).unwrap();
+        assert_eq!(task.priority, Priority::Medium);
+    }
+
+    #[tokio::test]
+    async fn tasks_are_queried_by_minimum_priority() {
+        let server = TestServer::new("tasks_are_queried_by_minimum_priority").await;
+        let project_id = server.create_project().await;
+        let mut task_ids = Vec::new();
+        for priority in ["Low", "Medium", "High", "Critical"] {
+            let task_id = server.create_task(project_id, priority).await;
+            let (status, _) = server
+                .post(
+                    "/task/priority",
+                    json!({"project_id": project_id, "task_id": task_id, "new_priority": priority}),
+                )
+                .await;
+            assert_eq!(status, StatusCode::OK);
+            task_ids.push(task_id);
+        }
+        let matched = |response: &serde_json::Value| {
+            response["tasks"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|entry| entry["task"]["id"].as_u64().unwrap() as usize)
+                .collect::<Vec<_>>()
+        };
+        let (status, response) = server
+            .post("/tasks/query", json!({"priority_min": "High"}))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(matched(&response), task_ids[2..]);
+        // Both filters have to match
+        let (_, response) = server
+            .post(
+                "/tasks/query",
+                json!({"priority_min": "Medium", "priorities": ["Low", "High"]}),
+            )
+            .await;
+        assert_eq!(matched(&response), [task_ids[2]]);
+    }
+
+    #[tokio::test]
+    async fn changes_made_during_a_flush_stay_pending() {
+        let server =
+            TestServer::with_config("changes_made_during_a_flush_stay_pending", |config| {
+                config.flush_cooldown = Some(Duration::from_secs(3600));
+            })
+            .await;
+        let project_id = server.create_project().await;
+        assert_eq!(read_state(&server.app_state).pending_mutations, 0);
+        let flush_lock = read_state(&server.app_state).flush_lock.clone();
+        let flushing = flush_lock.lock().await;
+        // Waits on the flush lock without holding the state lock, so requests are still served
+        let flush = tokio::spawn({
+            let app_state = server.app_state.clone();
+            async move { flush_database(&app_state).await.is_ok() }
+        });
+        server.create_task(project_id, "Task").await;
+        let (status, _) = server
+            .call(
+                Method::GET,
+                &format!("/project?project_id={}", project_id),
+                json!({}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        drop(flushing);
+        assert!(flush.await.unwrap());
+        assert_eq!(read_state(&server.app_state).pending_mutations, 0);
+        // A change which comes in after the database was serialized is left for the next flush
+        let mut app = write_state(&server.app_state);
+        app.pending_mutations = 3;
+        app.finish_flush(2, Instant::now(), Ok(())).unwrap();
+        assert_eq!(app.pending_mutations, 1);
+        assert!(app.flush_deferred);
+    }
+
+    #[tokio::test]
+    async fn effort_totals_saturate() {
+        let server = TestServer::new("effort_totals_saturate").await;
+        let project_id = server.create_project().await;
+        let task_id = server.create_task(project_id, "Task").await;
+        let dependency = server.create_task(project_id, "Dependency").await;
+        let (status, _) = add_dependency(&server, project_id, task_id, dependency).await;
+        assert_eq!(status, StatusCode::OK);
+        for id in [task_id, dependency] {
+            let (status, _) = server
+                .post(
+                    "/task/estimate",
+                    json!({"project_id": project_id, "task_id": id, "estimate_minutes": u64::MAX}),
+                )
+                .await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        let (status, response) = server
+            .call(
+                Method::GET,
+                "/task/subtree_effort",
+                json!({"project_id": project_id, "task_id": task_id}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response["total_minutes"], u64::MAX);
+        assert_eq!(response["remaining_minutes"], u64::MAX);
+        let (status, response) = server.call(Method::GET, "/report/load", json!({})).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response["unassigned"]["remaining_minutes"], u64::MAX);
+    }
+
+    #[tokio::test]
+    async fn chains_are_checked_for_cycles_in_projects_with_one() {
+        let server = TestServer::new("chains_are_checked_for_cycles_in_projects_with_one").await;
+        let project_id = server.create_project().await;
+        let mut ids = Vec::new();
+        for title in ["A", "B", "C", "D", "E"] {
+            ids.push(server.create_task(project_id, title).await);
+        }
+        let [a, b, c, d, e] = ids[..] else {
+            unreachable!()
+        };
+        {
+            // As an older database may have it, from before cycles were rejected
+            let mut app = write_state(&server.app_state);
+            let project = app.database.find_project_by_id_mut(project_id).unwrap();
+            project
+                .find_task_by_id_mut(a)
+                .unwrap()
+                .dependencies
+                .insert(b);
+            project
+                .find_task_by_id_mut(b)
+                .unwrap()
+                .dependencies
+                .insert(a);
+            project
+                .find_task_by_id_mut(d)
+                .unwrap()
+                .dependencies
+                .insert(c);
+        }
+        let revision = read_state(&server.app_state).database.revision;
+        // E becomes a dependency of D before C depending on D closes a cycle, and is rolled back
+        let (status, response) = server
+            .post(
+                "/task/chain",
+                json!({"project_id": project_id, "task_ids": [e, d, c]}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(response["code"], "CYCLE_DETECTED");
+        {
+            let app = read_state(&server.app_state);
+            assert_eq!(app.database.revision, revision);
+            let project = app.database.find_project_by_id(project_id).unwrap();
+            let d = project.find_task_by_id(d).unwrap();
+            assert_eq!(d.dependencies, HashSet::from([c]));
+            assert!(project.find_task_by_id(c).unwrap().dependencies.is_empty());
+        }
+        // Chains which don't close a cycle still work next to the existing one
+        let (status, response) = server
+            .post(
+                "/task/chain",
+                json!({"project_id": project_id, "task_ids": [c, e]}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(response["edges"], json!([{"task_id": e, "dependency": c}]));
+    }
+
+    #[tokio::test]
+    async fn queried_tags_are_normalized() {
+        let server = TestServer::new("queried_tags_are_normalized").await;
+        let project_id = server.create_project().await;
+        let tagged = server.create_task(project_id, "Tagged").await;
+        let legacy = server.create_task(project_id, "Legacy").await;
+        server.create_task(project_id, "Untagged").await;
+        let (status, _) = server
+            .post(
+                "/task/tag",
+                json!({"project_id": project_id, "task_id": tagged, "tag": "backend", "action": "Add"}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        {
+            // As an older database may have it, from before tags were normalized
+            let mut app = write_state(&server.app_state);
+            let project = app.database.find_project_by_id_mut(project_id).unwrap();
+            let task = project.find_task_by_id_mut(legacy).unwrap();
+            task.tags.insert("Backend".to_owned());
+        }
+        let matched = |response: &serde_json::Value| {
+            response["tasks"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|entry| entry["task"]["id"].as_u64().unwrap() as usize)
+                .collect::<Vec<_>>()
+        };
+        let (status, response) = server
+            .post("/tasks/query", json!({"tags": [" BackEnd "]}))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(matched(&response), [tagged, legacy]);
+        let (status, _) = server
+            .post(
+                "/query/save",
+                json!({"name": "Backend", "query": {"tags": ["Backend"]}}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let request = Request::get("/query/run?name=Backend")
+            .body(Body::empty())
+            .unwrap();
+        let (status, response) = server.send(request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(matched(&response), [tagged, legacy]);
+    }
+
+    #[tokio::test]
+    async fn requests_which_change_nothing_send_no_event() {
+        let server = TestServer::new("requests_which_change_nothing_send_no_event").await;
+        let project_id = server.create_project().await;
+        let task_id = server.create_task(project_id, "Task").await;
+        let mut events = read_state(&server.app_state)
+            .change_events
+            .as_ref()
+            .unwrap()
+            .subscribe();
+        let delete_task = json!({"project_id": project_id, "task_id": task_id});
+        for _ in 0..2 {
+            let (status, _) = server.post("/task/delete", delete_task.clone()).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.kind, "task/delete");
+        assert_eq!(event.task_id, Some(task_id));
+        assert!(events.try_recv().is_err());
+        let task_id = server.create_task(project_id, "Task").await;
+        let target = server.create_task(project_id, "Target").await;
+        for _ in 0..2 {
+            assert_eq!(events.try_recv().unwrap().kind, "task/create");
+        }
+        let relation = json!({"project_id": project_id, "task_id": task_id, "kind": "RelatesTo", "target": target});
+        for _ in 0..2 {
+            let (status, _) = server.post("/task/relation/add", relation.clone()).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        assert_eq!(events.try_recv().unwrap().kind, "task/relation/add");
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn unchanged_relations_are_not_recorded() {
+        let server = TestServer::new("unchanged_relations_are_not_recorded").await;
+        let project_id = server.create_project().await;
+        let task_id = server.create_task(project_id, "Task").await;
+        let target = server.create_task(project_id, "Target").await;
+        let relation = json!({"project_id": project_id, "task_id": task_id, "kind": "RelatesTo", "target": target});
+        let version_and_revision = || {
+            let app = read_state(&server.app_state);
+            let project = app.database.find_project_by_id(project_id).unwrap();
+            let version = project.find_task_by_id(task_id).unwrap().version;
+            (version, app.database.revision)
+        };
+        let (status, _) = server.post("/task/relation/add", relation.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        let added = version_and_revision();
+        let (status, _) = server.post("/task/relation/add", relation.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(version_and_revision(), added);
+        let (status, _) = server.post("/task/relation/remove", relation.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        let removed = version_and_revision();
+        assert_eq!(removed, (added.0 + 1, added.1 + 1));
+        let (status, _) = server.post("/task/relation/remove", relation).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(version_and_revision(), removed);
+    }
+
+    #[tokio::test]
+    async fn snapshots_round_trip() {
+        let server = TestServer::new("snapshots_round_trip").await;
+        let kept = server.create_project().await;
+        let snapshot = || server.post("/admin/snapshot", json!({"name": "kept"}));
+        // Both write the same file, one after the other
+        let (first, second) = tokio::join!(snapshot(), snapshot());
+        assert_eq!(first.0, StatusCode::OK);
+        assert_eq!(second.0, StatusCode::OK);
+        let dropped = server.create_project().await;
+        let (status, response) = server.post("/admin/restore", json!({"name": "kept"})).await;
+        assert_eq!(status, StatusCode::OK);
+        {
+            let app = read_state(&server.app_state);
+            assert!(app.database.find_project_by_id(kept).is_ok());
+            assert!(app.database.find_project_by_id(dropped).is_err());
+        }
+        // The backup is a snapshot like any other, so the restore can be undone
+        let backup = response["backup"].as_str().unwrap();
+        let (status, _) = server.post("/admin/restore", json!({"name": backup})).await;
+        assert_eq!(status, StatusCode::OK);
+        let app = read_state(&server.app_state);
+        assert!(app.database.find_project_by_id(kept).is_ok());
+        assert!(app.database.find_project_by_id(dropped).is_ok());
+        // The database file matches the restored database
+        let written = std::fs::read(&app.database_path).unwrap();
+        let written: Database = serde_json::from_slice(&written).unwrap();
+        assert_eq!(written.revision, app.database.revision);
+    }
+
+    #[tokio::test]
+    async fn bulk_state_changes_reject_repeated_tasks() {
+        let server = TestServer::new("bulk_state_changes_reject_repeated_tasks").await;
+        let project_id = server.create_project().await;
+        let task_id = server.create_task(project_id, "Task").await;
+        let revision = read_state(&server.app_state).database.revision;
+        let (status, response) = server
+            .post(
+                "/task/state/bulk",
+                json!({"project_id": project_id, "changes": [
+                    {"task_id": task_id, "new_state": "InProgress"},
+                    {"task_id": task_id, "new_state": "Blocked"},
+                ]}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(response["code"], "INVALID_VALUE");
+        let app = read_state(&server.app_state);
+        assert_eq!(app.database.revision, revision);
+        let project = app.database.find_project_by_id(project_id).unwrap();
+        assert_eq!(project.find_task_by_id(task_id).unwrap().state, State::Todo);
+    }
+
+    #[tokio::test]
+    async fn untouched_tasks_go_stale() {
+        let server = TestServer::new("untouched_tasks_go_stale").await;
+        let project_id = server.create_project().await;
+        let old = server.create_task(project_id, "Old").await;
+        server.create_task(project_id, "New").await;
+        {
+            let mut app = write_state(&server.app_state);
+            let project = app.database.find_project_by_id_mut(project_id).unwrap();
+            let task = project.find_task_by_id_mut(old).unwrap();
+            assert!(task.log.is_empty());
+            task.created_at -= chrono::Duration::days(STALE_TASK_DAYS + 1);
+        }
+        let (status, response) = server
+            .call(
+                Method::GET,
+                "/project/health",
+                json!({"project_id": project_id}),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let stale = response["factors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|factor| factor["name"] == "stale")
+            .unwrap();
+        assert_eq!(stale["affected_tasks"], 1);
+    }
 }
@@ -0,0 +1,252 @@
+// Metrics computed over a project's tasks and their logs, for the reporting endpoints
+
+use std::collections::{BTreeMap, HashSet};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+use crate::{LogEntryType, Project, State, Task};
+
+// Time of the entry which moved a task into its current state, if the log still has it
+pub(crate) fn entered_current_state(task: &Task) -> Option<DateTime<Utc>> {
+    task.log
+        .iter()
+        .rev()
+        .find_map(|entry| match entry.entry_type {
+            LogEntryType::StateChangedTo(state) if state == task.state => Some(entry.timestamp),
+            _ => None,
+        })
+}
+
+fn first_entered(task: &Task, wanted: State) -> Option<DateTime<Utc>> {
+    task.log.iter().find_map(|entry| match entry.entry_type {
+        LogEntryType::StateChangedTo(state) if state == wanted => Some(entry.timestamp),
+        _ => None,
+    })
+}
+
+fn mean(values: &[f64]) -> Option<f64> {
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.iter().sum::<f64>() / values.len() as f64)
+    }
+}
+
+// Expects the values to be sorted
+fn median(values: &[f64]) -> Option<f64> {
+    match values.len() {
+        0 => None,
+        len if len % 2 == 1 => Some(values[len / 2]),
+        len => Some((values[len / 2 - 1] + values[len / 2]) / 2.0),
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct Progress {
+    total_tasks: usize,
+    by_state: BTreeMap<String, usize>,
+    // Fraction of tasks which are done, ignoring tasks in any other terminal state, like cancelled
+    // tasks
+    done_fraction: f64,
+}
+
+pub(crate) fn progress(project: &Project, terminal_states: &HashSet<State>) -> Progress {
+    let mut by_state = BTreeMap::new();
+    for task in &project.tasks {
+        *by_state.entry(format!("{:?}", task.state)).or_default() += 1;
+    }
+    let done = by_state.get("Done").copied().unwrap_or(0);
+    let ignored = project
+        .tasks
+        .iter()
+        .filter(|task| task.state != State::Done && terminal_states.contains(&task.state))
+        .count();
+    let counted = project.tasks.len() - ignored;
+    Progress {
+        total_tasks: project.tasks.len(),
+        by_state,
+        done_fraction: if counted == 0 {
+            0.0
+        } else {
+            done as f64 / counted as f64
+        },
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct Throughput {
+    done_last_7_days: usize,
+    done_last_30_days: usize,
+}
+
+// Counts tasks which are currently done, by when they were last marked done
+pub(crate) fn throughput(project: &Project, now: DateTime<Utc>) -> Throughput {
+    let done_times = project
+        .tasks
+        .iter()
+        .filter(|task| task.state == State::Done)
+        .filter_map(entered_current_state)
+        .collect::<Vec<_>>();
+    let done_since = |days| {
+        let since = now - Duration::days(days);
+        done_times.iter().filter(|time| **time >= since).count()
+    };
+    Throughput {
+        done_last_7_days: done_since(7),
+        done_last_30_days: done_since(30),
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct CycleTime {
+    // Number of done tasks which went through InProgress, which are the only ones measured
+    measured_tasks: usize,
+    average_hours: Option<f64>,
+    median_hours: Option<f64>,
+}
+
+// Measures, for each done task, the time from first starting work on it to finally marking it done
+pub(crate) fn cycle_time(project: &Project) -> CycleTime {
+    let mut hours = project
+        .tasks
+        .iter()
+        .filter(|task| task.state == State::Done)
+        .filter_map(|task| {
+            let started = first_entered(task, State::InProgress)?;
+            let done = entered_current_state(task)?;
+            Some((done - started).num_seconds() as f64 / 3600.0)
+        })
+        .collect::<Vec<_>>();
+    hours.sort_by(|a, b| a.total_cmp(b));
+    CycleTime {
+        measured_tasks: hours.len(),
+        average_hours: mean(&hours),
+        median_hours: median(&hours),
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct Aging {
+    // Number of open tasks whose log says when they entered their current state
+    measured_tasks: usize,
+    average_days: Option<f64>,
+    oldest_days: Option<f64>,
+    oldest_task_id: Option<usize>,
+}
+
+// Measures how long each open task has been sitting in its current state
+pub(crate) fn aging(
+    project: &Project,
+    now: DateTime<Utc>,
+    terminal_states: &HashSet<State>,
+) -> Aging {
+    let ages = project
+        .tasks
+        .iter()
+        .filter(|task| !terminal_states.contains(&task.state))
+        .filter_map(|task| {
+            let since = entered_current_state(task)?;
+            Some((task.id, (now - since).num_seconds() as f64 / 86400.0))
+        })
+        .collect::<Vec<_>>();
+    let oldest = ages.iter().max_by(|a, b| a.1.total_cmp(&b.1));
+    Aging {
+        measured_tasks: ages.len(),
+        average_days: if ages.is_empty() {
+            None
+        } else {
+            Some(ages.iter().map(|(_, age)| age).sum::<f64>() / ages.len() as f64)
+        },
+        oldest_days: oldest.map(|(_, age)| *age),
+        oldest_task_id: oldest.map(|(id, _)| *id),
+    }
+}
+
+// Counts of tasks by how their actual time compared to their estimate
+#[derive(Default, Serialize, Debug)]
+pub(crate) struct RatioDistribution {
+    // Actual time under half the estimate
+    under_half: usize,
+    // Actual time from half the estimate up to 90% of it
+    under: usize,
+    // Actual time within 10% of the estimate either way
+    on_target: usize,
+    // Actual time from 110% of the estimate up to twice it
+    over: usize,
+    // Actual time at least twice the estimate
+    over_double: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct EstimationAccuracy {
+    // Number of done tasks with both a non-zero estimate and an actual time
+    measured_tasks: usize,
+    // Number of done tasks left out for missing either value
+    excluded_tasks: usize,
+    // Average and median of actual time divided by estimate
+    average_ratio: Option<f64>,
+    median_ratio: Option<f64>,
+    distribution: RatioDistribution,
+}
+
+// Compares actual time against the estimate over done tasks. Tasks estimated at zero minutes have
+// no meaningful ratio, so they are excluded too.
+pub(crate) fn estimation_accuracy<'a>(tasks: impl Iterator<Item = &'a Task>) -> EstimationAccuracy {
+    let mut excluded_tasks = 0;
+    let mut ratios = Vec::new();
+    for task in tasks.filter(|task| task.state == State::Done) {
+        match (task.estimate_minutes, task.actual_minutes) {
+            (Some(estimate), Some(actual)) if estimate > 0 => {
+                ratios.push(actual as f64 / estimate as f64)
+            }
+            _ => excluded_tasks += 1,
+        }
+    }
+    ratios.sort_by(|a, b| a.total_cmp(b));
+    let mut distribution = RatioDistribution::default();
+    for &ratio in &ratios {
+        let bucket = if ratio < 0.5 {
+            &mut distribution.under_half
+        } else if ratio < 0.9 {
+            &mut distribution.under
+        } else if ratio <= 1.1 {
+            &mut distribution.on_target
+        } else if ratio < 2.0 {
+            &mut distribution.over
+        } else {
+            &mut distribution.over_double
+        };
+        *bucket += 1;
+    }
+    EstimationAccuracy {
+        measured_tasks: ratios.len(),
+        excluded_tasks,
+        average_ratio: mean(&ratios),
+        median_ratio: median(&ratios),
+        distribution,
+    }
+}
+
+// Counts the times tasks were marked done within [since, until), by calendar day in the given time
+// zone. A task which was marked done more than once counts once for each time.
+pub(crate) fn completions_by_day<'a>(
+    tasks: impl Iterator<Item = &'a Task>,
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+    time_zone: chrono_tz::Tz,
+) -> BTreeMap<String, usize> {
+    let mut days = BTreeMap::new();
+    for entry in tasks.flat_map(|task| task.log.iter()) {
+        let done = matches!(entry.entry_type, LogEntryType::StateChangedTo(State::Done));
+        if done && entry.timestamp >= since && entry.timestamp < until {
+            let day = entry
+                .timestamp
+                .with_timezone(&time_zone)
+                .naive_local()
+                .date();
+            *days.entry(day.to_string()).or_default() += 1;
+        }
+    }
+    days
+}
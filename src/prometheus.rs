@@ -0,0 +1,116 @@
+// Counters about the server itself, and the /metrics output in the Prometheus text format
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::State;
+
+pub(crate) struct ServerMetrics {
+    // Requests handled since startup, by method and path
+    requests: Mutex<BTreeMap<(String, String), u64>>,
+    flushes: AtomicU64,
+    failed_flushes: AtomicU64,
+    // Time spent on all flushes, failed ones included
+    flush_micros: AtomicU64,
+}
+
+pub(crate) static SERVER_METRICS: ServerMetrics = ServerMetrics {
+    requests: Mutex::new(BTreeMap::new()),
+    flushes: AtomicU64::new(0),
+    failed_flushes: AtomicU64::new(0),
+    flush_micros: AtomicU64::new(0),
+};
+
+impl ServerMetrics {
+    pub(crate) fn record_request(&self, method: &str, path: &str) {
+        let mut requests = self.requests.lock().unwrap();
+        *requests
+            .entry((method.to_owned(), path.to_owned()))
+            .or_default() += 1;
+    }
+
+    pub(crate) fn record_flush(&self, duration: Duration, succeeded: bool) {
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        if !succeeded {
+            self.failed_flushes.fetch_add(1, Ordering::Relaxed);
+        }
+        self.flush_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+fn write_header(output: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(output, "# HELP {} {}", name, help).unwrap();
+    writeln!(output, "# TYPE {} {}", name, kind).unwrap();
+}
+
+// Label values here are only ever known paths, methods and state names, so none of them need
+// escaping
+pub(crate) fn render(project_count: usize, tasks_by_state: &[(State, usize)]) -> String {
+    let mut output = String::new();
+    write_header(
+        &mut output,
+        "btasks_projects",
+        "gauge",
+        "Number of projects.",
+    );
+    writeln!(output, "btasks_projects {}", project_count).unwrap();
+    write_header(&mut output, "btasks_tasks", "gauge", "Number of tasks.");
+    let task_count = tasks_by_state.iter().map(|(_, count)| count).sum::<usize>();
+    writeln!(output, "btasks_tasks {}", task_count).unwrap();
+    write_header(
+        &mut output,
+        "btasks_tasks_by_state",
+        "gauge",
+        "Number of tasks in each state.",
+    );
+    for (state, count) in tasks_by_state {
+        writeln!(
+            output,
+            "btasks_tasks_by_state{{state=\"{:?}\"}} {}",
+            state, count
+        )
+        .unwrap();
+    }
+    write_header(
+        &mut output,
+        "btasks_requests_total",
+        "counter",
+        "Requests handled since startup, by method and path.",
+    );
+    for ((method, path), count) in SERVER_METRICS.requests.lock().unwrap().iter() {
+        writeln!(
+            output,
+            "btasks_requests_total{{method=\"{}\",path=\"{}\"}} {}",
+            method, path, count
+        )
+        .unwrap();
+    }
+    write_header(
+        &mut output,
+        "btasks_flush_failures_total",
+        "counter",
+        "Flushes of the database to disk which failed.",
+    );
+    let failed_flushes = SERVER_METRICS.failed_flushes.load(Ordering::Relaxed);
+    writeln!(output, "btasks_flush_failures_total {}", failed_flushes).unwrap();
+    write_header(
+        &mut output,
+        "btasks_flush_duration_seconds",
+        "summary",
+        "Time spent flushing the database to disk.",
+    );
+    let flush_seconds = SERVER_METRICS.flush_micros.load(Ordering::Relaxed) as f64 / 1e6;
+    writeln!(
+        output,
+        "btasks_flush_duration_seconds_sum {}",
+        flush_seconds
+    )
+    .unwrap();
+    let flushes = SERVER_METRICS.flushes.load(Ordering::Relaxed);
+    writeln!(output, "btasks_flush_duration_seconds_count {}", flushes).unwrap();
+    output
+}
@@ -0,0 +1,148 @@
+// Deserializes query strings, e.g. "project_id=0&task_id=3", into the request structs which are
+// otherwise read from a JSON body. Values come as text, so each is parsed as whatever type its
+// field asks for. Lists are given comma-separated, e.g. "tags=backend,urgent".
+
+use serde::de::value::{Error, MapDeserializer, SeqDeserializer};
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use serde::forward_to_deserialize_any;
+
+pub(crate) fn from_query<T: DeserializeOwned>(query: &str) -> Result<T, Error> {
+    let params = query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            let name = decode(name);
+            let value = QueryValue {
+                name: name.clone(),
+                value: decode(value),
+            };
+            (name, value)
+        });
+    T::deserialize(MapDeserializer::new(params))
+}
+
+// Undoes percent-encoding, and the "+" which forms use for spaces
+fn decode(text: &str) -> String {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let escaped = rest
+                    .get(..2)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match escaped {
+                    Some(escaped) => {
+                        bytes.push(escaped);
+                        rest = &rest[2..];
+                    }
+                    None => bytes.push(b'%'),
+                }
+            }
+            _ => bytes.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+// The name is kept to say which parameter is wrong, since serde doesn't
+struct QueryValue {
+    name: String,
+    value: String,
+}
+
+impl QueryValue {
+    fn parse<T: std::str::FromStr>(&self) -> Result<T, Error> {
+        self.value.parse().map_err(|_| {
+            de::Error::custom(format!(
+                "invalid value `{}` for `{}`",
+                self.value, self.name
+            ))
+        })
+    }
+}
+
+impl<'de> IntoDeserializer<'de, Error> for QueryValue {
+    type Deserializer = QueryValue;
+
+    fn into_deserializer(self) -> QueryValue {
+        self
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                visitor.$visit(self.parse()?)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for QueryValue {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_string(self.value)
+    }
+
+    deserialize_parsed! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+    }
+
+    // There's no way to write null, so a parameter which is given is always Some. Leaving it out
+    // gives None.
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let items = self
+            .value
+            .split(',')
+            .filter(|item| !item.is_empty())
+            .map(|item| QueryValue {
+                name: self.name.clone(),
+                value: item.to_owned(),
+            })
+            .collect::<Vec<_>>();
+        visitor.visit_seq(SeqDeserializer::new(items.into_iter()))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_enum(self.value.into_deserializer())
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 char str string bytes byte_buf unit unit_struct tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
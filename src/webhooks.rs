@@ -0,0 +1,63 @@
+// Webhooks which clients register to hear about changes, and the delivery of events to them
+
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use hyper::{header, Body, Request};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum WebhookEvent {
+    StateChange,
+    TaskCreated,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Webhook {
+    // Checked to be an http:// URL when the webhook is registered
+    pub(crate) url: String,
+    pub(crate) events: BTreeSet<WebhookEvent>,
+}
+
+// Tries made at delivering each event, the first one included
+const DELIVERY_ATTEMPTS: u32 = 3;
+// Doubled after every failed try
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+async fn post(url: &hyper::Uri, body: &str) -> Result<(), String> {
+    let request = Request::post(url.clone())
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_owned()))
+        .expect("Failed to build request");
+    let response = tokio::time::timeout(ATTEMPT_TIMEOUT, hyper::Client::new().request(request))
+        .await
+        .map_err(|_| "Timed out".to_owned())?
+        .map_err(|error| error.to_string())?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("Responded with {}", response.status()))
+    }
+}
+
+// Sent in the background, so the request which made the change never waits on the webhook
+pub(crate) fn deliver(url: hyper::Uri, body: String) {
+    tokio::spawn(async move {
+        let mut delay = FIRST_RETRY_DELAY;
+        for attempt in 1..=DELIVERY_ATTEMPTS {
+            match post(&url, &body).await {
+                Ok(()) => return,
+                Err(error) => eprintln!(
+                    "ERROR: Could not deliver webhook to {} (attempt {} of {}): {}",
+                    url, attempt, DELIVERY_ATTEMPTS, error
+                ),
+            }
+            if attempt < DELIVERY_ATTEMPTS {
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+        }
+    });
+}